@@ -1,21 +1,22 @@
-#![feature(test)]
-extern crate rand;
-extern crate stream_vbyte64;
-extern crate test;
-
-use rand::{Rng, XorShiftRng};
-use test::Bencher;
+// The AVX2 kernels use different tables with the `compact-tables` feature. To compare the two, save
+// a baseline with the default tables and then run against it with the feature enabled:
+//
+//     cargo bench -- --save-baseline full avx2
+//     cargo bench --features compact-tables -- --baseline full avx2
+use criterion::{criterion_group, criterion_main, Bencher, Criterion, Throughput};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 fn one_byte_values() -> Vec<u64> {
     (0..4096).map(|v| v & 0xff).collect()
 }
 
 fn random_values() -> Vec<u64> {
-    let mut rng = XorShiftRng::new_unseeded();
+    let mut rng = StdRng::seed_from_u64(0);
     (0..4096)
         .map(|_| {
-            let bytes = rng.gen_range(0, 9);
-            rng.gen::<u64>() & ((1 << bytes * 8) - 1)
+            let bytes = rng.gen_range(0..9);
+            rng.gen::<u64>() & (1u64 << (bytes * 8)).wrapping_sub(1)
         })
         .collect()
 }
@@ -27,102 +28,128 @@ fn compressed_data_len(values: &[u64], b: &mut Bencher) {
     b.iter(|| stream_vbyte64::compressed_data_len(values.len(), &buf));
 }
 
+fn encoded_len(values: &[u64], b: &mut Bencher) {
+    b.iter(|| stream_vbyte64::encoded_len(values));
+}
+
 fn scalar_encode(values: &[u64], b: &mut Bencher) {
     let mut keys = vec![0; stream_vbyte64::keys_len(values.len())];
     let mut data = vec![0; values.len() * 8];
 
-    b.iter(|| unsafe {
-        stream_vbyte64::encode_scalar(&values, &mut keys, &mut data)
-    });
-    b.bytes = 8 * values.len() as u64;
+    b.iter(|| unsafe { stream_vbyte64::encode_scalar(values, &mut keys, &mut data) });
 }
 
 fn scalar_decode(values: &[u64], b: &mut Bencher) {
     let mut keys = vec![0; stream_vbyte64::keys_len(values.len())];
     let mut data = vec![0; values.len() * 8];
-    unsafe { stream_vbyte64::encode_scalar(&values, &mut keys, &mut data) };
+    unsafe { stream_vbyte64::encode_scalar(values, &mut keys, &mut data) };
     let mut decoded = vec![0; values.len()];
 
-    b.iter(|| unsafe {
-        stream_vbyte64::decode_scalar(&mut decoded, &keys, &data)
-    });
-    b.bytes = 8 * values.len() as u64;
+    b.iter(|| unsafe { stream_vbyte64::decode_scalar(&mut decoded, &keys, &data) });
 }
 
-fn encode(values: &[u64], b: &mut Bencher) {
-    let data_len = stream_vbyte64::max_compressed_len(values.len());
-    let mut buf = vec![0; data_len];
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn ssse3_encode(values: &[u64], b: &mut Bencher) {
+    let mut keys = vec![0; stream_vbyte64::keys_len(values.len())];
+    let mut data = vec![0; values.len() * 8];
 
-    b.iter(|| stream_vbyte64::encode(&values, &mut buf));
-    b.bytes = 8 * values.len() as u64
+    b.iter(|| unsafe { stream_vbyte64::encode_ssse3(values, &mut keys, &mut data) });
 }
 
-fn decode(values: &[u64], b: &mut Bencher) {
-    let data_len = stream_vbyte64::max_compressed_len(values.len());
-    let mut buf = vec![0; data_len];
-    stream_vbyte64::encode(&values, &mut buf);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn ssse3_decode(values: &[u64], b: &mut Bencher) {
+    let mut keys = vec![0; stream_vbyte64::keys_len(values.len())];
+    let mut data = vec![0; values.len() * 8];
+    unsafe { stream_vbyte64::encode_scalar(values, &mut keys, &mut data) };
     let mut decoded = vec![0; values.len()];
 
-    b.iter(|| stream_vbyte64::decode(&mut decoded, &buf));
-    b.bytes = 8 * values.len() as u64
+    b.iter(|| unsafe { stream_vbyte64::decode_ssse3(&mut decoded, &keys, &data) });
 }
 
-#[bench]
-fn compressed_data_len_one_byte(b: &mut Bencher) {
-    let values = one_byte_values();
-    compressed_data_len(&values, b);
-}
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn avx2_encode(values: &[u64], b: &mut Bencher) {
+    let mut keys = vec![0; stream_vbyte64::keys_len(values.len())];
+    let mut data = vec![0; values.len() * 8];
 
-#[bench]
-fn compressed_data_len_random(b: &mut Bencher) {
-    let values = random_values();
-    compressed_data_len(&values, b);
+    b.iter(|| unsafe { stream_vbyte64::encode_avx(values, &mut keys, &mut data) });
 }
 
-#[bench]
-fn scalar_encode_one_byte(b: &mut Bencher) {
-    let values = one_byte_values();
-    scalar_encode(&values, b);
-}
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn avx2_table_free_encode(values: &[u64], b: &mut Bencher) {
+    let mut keys = vec![0; stream_vbyte64::keys_len(values.len())];
+    let mut data = vec![0; values.len() * 8];
 
-#[bench]
-fn scalar_decode_one_byte(b: &mut Bencher) {
-    let values = one_byte_values();
-    scalar_decode(&values, b);
+    b.iter(|| unsafe { stream_vbyte64::encode_avx_table_free(values, &mut keys, &mut data) });
 }
 
-#[bench]
-fn scalar_encode_random(b: &mut Bencher) {
-    let values = random_values();
-    scalar_encode(&values, b);
-}
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn avx2_decode(values: &[u64], b: &mut Bencher) {
+    let mut keys = vec![0; stream_vbyte64::keys_len(values.len())];
+    let mut data = vec![0; values.len() * 8];
+    unsafe { stream_vbyte64::encode_scalar(values, &mut keys, &mut data) };
+    let mut decoded = vec![0; values.len()];
 
-#[bench]
-fn scalar_decode_random(b: &mut Bencher) {
-    let values = random_values();
-    scalar_decode(&values, b);
+    b.iter(|| unsafe { stream_vbyte64::decode_avx(&mut decoded, &keys, &data) });
 }
 
-#[bench]
-fn encode_one_byte(b: &mut Bencher) {
-    let values = one_byte_values();
-    encode(&values, b);
-}
+fn swar_decode(values: &[u64], b: &mut Bencher) {
+    let mut keys = vec![0; stream_vbyte64::keys_len(values.len())];
+    let mut data = vec![0; values.len() * 8];
+    unsafe { stream_vbyte64::encode_scalar(values, &mut keys, &mut data) };
+    let mut decoded = vec![0; values.len()];
 
-#[bench]
-fn decode_one_byte(b: &mut Bencher) {
-    let values = one_byte_values();
-    decode(&values, b);
+    b.iter(|| unsafe { stream_vbyte64::decode_swar(&mut decoded, &keys, &data) });
 }
 
-#[bench]
-fn encode_random(b: &mut Bencher) {
-    let values = random_values();
-    encode(&values, b);
+fn encode(values: &[u64], b: &mut Bencher) {
+    let data_len = stream_vbyte64::max_compressed_len(values.len());
+    let mut buf = vec![0; data_len];
+
+    b.iter(|| stream_vbyte64::encode(values, &mut buf));
 }
 
-#[bench]
-fn decode_random(b: &mut Bencher) {
-    let values = random_values();
-    decode(&values, b);
+fn decode(values: &[u64], b: &mut Bencher) {
+    let data_len = stream_vbyte64::max_compressed_len(values.len());
+    let mut buf = vec![0; data_len];
+    stream_vbyte64::encode(values, &mut buf);
+    let mut decoded = vec![0; values.len()];
+
+    b.iter(|| stream_vbyte64::decode(&mut decoded, &buf));
 }
+
+fn benches(c: &mut Criterion) {
+    let distributions = [("one_byte", one_byte_values()), ("random", random_values())];
+
+    for (name, values) in &distributions {
+        c.bench_function(&format!("compressed_data_len_{}", name), |b| {
+            compressed_data_len(values, b)
+        });
+        c.bench_function(&format!("encoded_len_{}", name), |b| encoded_len(values, b));
+
+        let mut group = c.benchmark_group(*name);
+        group.throughput(Throughput::Bytes(8 * values.len() as u64));
+        group.bench_function("scalar_encode", |b| scalar_encode(values, b));
+        group.bench_function("scalar_decode", |b| scalar_decode(values, b));
+        group.bench_function("swar_decode", |b| swar_decode(values, b));
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if is_x86_feature_detected!("ssse3") {
+                group.bench_function("ssse3_encode", |b| ssse3_encode(values, b));
+                group.bench_function("ssse3_decode", |b| ssse3_decode(values, b));
+            }
+            if is_x86_feature_detected!("avx2") {
+                group.bench_function("avx2_encode", |b| avx2_encode(values, b));
+                group.bench_function("avx2_table_free_encode", |b| {
+                    avx2_table_free_encode(values, b)
+                });
+                group.bench_function("avx2_decode", |b| avx2_decode(values, b));
+            }
+        }
+        group.bench_function("encode", |b| encode(values, b));
+        group.bench_function("decode", |b| decode(values, b));
+        group.finish();
+    }
+}
+
+criterion_group!(benches_group, benches);
+criterion_main!(benches_group);
@@ -0,0 +1,429 @@
+use std::ptr;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::avx2;
+use crate::engine::{Backend, Engine};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::tables::PairShuffle;
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    not(feature = "compact-tables")
+))]
+use crate::tables::Shuffle;
+use crate::transform::Plain;
+use crate::{
+    check_keys_len, decode_scalar_alphabet, decode_single, encode_scalar_alphabet, encode_single,
+    keys_data_len_scalar, keys_len, max_compressed_len, tables, DecodeError,
+};
+
+/// The data lengths that the 8 codes of a key map to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Alphabet {
+    /// Codes map to lengths 1 through 8. This is the alphabet used by `encode` and `decode`.
+    #[default]
+    Standard,
+    /// Codes map to lengths 0 through 6 and 8.
+    ///
+    /// Zeros take no data bytes at all, at the cost of values needing 7 bytes taking 8.
+    Sparse,
+}
+
+impl Alphabet {
+    /// Returns the data length of each code.
+    pub fn lengths(self) -> [u8; 8] {
+        match self {
+            Alphabet::Standard => Standard::LENGTHS,
+            Alphabet::Sparse => Sparse::LENGTHS,
+        }
+    }
+}
+
+/// The static description of an alphabet used by the kernels.
+pub trait Codes: Sized {
+    const LENGTHS: [u8; 8];
+
+    /// The length of a block of 4 values indexed by its 12 bit key.
+    const LENGTH: &'static [u8; 4096];
+
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        not(feature = "compact-tables")
+    ))]
+    const DECODE_SHUFFLE_1: &'static [Shuffle; 4096];
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        not(feature = "compact-tables")
+    ))]
+    const DECODE_SHUFFLE_2: &'static [Shuffle; 4096];
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        not(feature = "compact-tables")
+    ))]
+    const ENCODE_SHUFFLE_1: &'static [Shuffle; 4096];
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        not(feature = "compact-tables")
+    ))]
+    const ENCODE_SHUFFLE_2: &'static [Shuffle; 4096];
+
+    /// Shuffles for a pair of values indexed by its 6 bit key.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    const PAIR_DECODE_SHUFFLE: &'static [PairShuffle; 64];
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    const PAIR_ENCODE_SHUFFLE: &'static [PairShuffle; 64];
+
+    /// The codes of the low and high 4 bytes of a value, indexed by a bitmap of which of those
+    /// bytes are nonzero, most significant byte first.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    const LOW_LANE_CODES: [i8; 16];
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    const HIGH_LANE_CODES: [i8; 16];
+
+    unsafe fn encode_single(value: u64, out: &mut *mut u8) -> u8;
+
+    unsafe fn decode_single(ptr: &mut *const u8, code: u8) -> u64;
+
+    /// Returns the total data length of the key groups in `keys`.
+    fn keys_data_len(_backend: Backend, keys: &[u8]) -> usize {
+        keys_data_len_scalar::<Self>(keys)
+    }
+}
+
+pub struct Standard;
+
+impl Codes for Standard {
+    const LENGTHS: [u8; 8] = tables::STANDARD;
+
+    const LENGTH: &'static [u8; 4096] = &tables::LENGTH;
+
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        not(feature = "compact-tables")
+    ))]
+    const DECODE_SHUFFLE_1: &'static [Shuffle; 4096] = &tables::DECODE_SHUFFLE_1;
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        not(feature = "compact-tables")
+    ))]
+    const DECODE_SHUFFLE_2: &'static [Shuffle; 4096] = &tables::DECODE_SHUFFLE_2;
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        not(feature = "compact-tables")
+    ))]
+    const ENCODE_SHUFFLE_1: &'static [Shuffle; 4096] = &tables::ENCODE_SHUFFLE_1;
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        not(feature = "compact-tables")
+    ))]
+    const ENCODE_SHUFFLE_2: &'static [Shuffle; 4096] = &tables::ENCODE_SHUFFLE_2;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    const PAIR_DECODE_SHUFFLE: &'static [PairShuffle; 64] = &tables::PAIR_DECODE_SHUFFLE;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    const PAIR_ENCODE_SHUFFLE: &'static [PairShuffle; 64] = &tables::PAIR_ENCODE_SHUFFLE;
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    const LOW_LANE_CODES: [i8; 16] = [0, 3, 2, 3, 1, 3, 2, 3, 0, 3, 2, 3, 1, 3, 2, 3];
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    const HIGH_LANE_CODES: [i8; 16] = [0, 7, 6, 7, 5, 7, 6, 7, 4, 7, 6, 7, 5, 7, 6, 7];
+
+    #[inline]
+    unsafe fn encode_single(value: u64, out: &mut *mut u8) -> u8 {
+        encode_single(value, out)
+    }
+
+    #[inline]
+    unsafe fn decode_single(ptr: &mut *const u8, code: u8) -> u64 {
+        decode_single(ptr, code)
+    }
+
+    fn keys_data_len(backend: Backend, keys: &[u8]) -> usize {
+        match backend {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Avx2 => unsafe { avx2::keys_data_len_avx(keys) },
+            _ => keys_data_len_scalar::<Self>(keys),
+        }
+    }
+}
+
+pub struct Sparse;
+
+impl Codes for Sparse {
+    const LENGTHS: [u8; 8] = tables::SPARSE;
+
+    const LENGTH: &'static [u8; 4096] = &tables::LENGTH_SPARSE;
+
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        not(feature = "compact-tables")
+    ))]
+    const DECODE_SHUFFLE_1: &'static [Shuffle; 4096] = &tables::DECODE_SHUFFLE_1_SPARSE;
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        not(feature = "compact-tables")
+    ))]
+    const DECODE_SHUFFLE_2: &'static [Shuffle; 4096] = &tables::DECODE_SHUFFLE_2_SPARSE;
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        not(feature = "compact-tables")
+    ))]
+    const ENCODE_SHUFFLE_1: &'static [Shuffle; 4096] = &tables::ENCODE_SHUFFLE_1_SPARSE;
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        not(feature = "compact-tables")
+    ))]
+    const ENCODE_SHUFFLE_2: &'static [Shuffle; 4096] = &tables::ENCODE_SHUFFLE_2_SPARSE;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    const PAIR_DECODE_SHUFFLE: &'static [PairShuffle; 64] = &tables::PAIR_DECODE_SHUFFLE_SPARSE;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    const PAIR_ENCODE_SHUFFLE: &'static [PairShuffle; 64] = &tables::PAIR_ENCODE_SHUFFLE_SPARSE;
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    const LOW_LANE_CODES: [i8; 16] = [0, 4, 3, 4, 2, 4, 3, 4, 1, 4, 3, 4, 2, 4, 3, 4];
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    const HIGH_LANE_CODES: [i8; 16] = [0, 7, 7, 7, 6, 7, 7, 7, 5, 7, 7, 7, 6, 7, 7, 7];
+
+    #[inline]
+    unsafe fn encode_single(value: u64, out: &mut *mut u8) -> u8 {
+        let bytes = 8 - value.leading_zeros() as u8 / 8;
+        let code = if bytes >= 7 { 7 } else { bytes };
+        let len = Self::LENGTHS[code as usize] as usize;
+        ptr::copy_nonoverlapping(&value.to_le() as *const u64 as *const u8, *out, len);
+        *out = out.add(len);
+        code
+    }
+
+    #[inline]
+    unsafe fn decode_single(ptr: &mut *const u8, code: u8) -> u64 {
+        let len = Self::LENGTHS[code as usize] as usize;
+        let mut value = 0u64;
+        ptr::copy_nonoverlapping(*ptr, &mut value as *mut u64 as *mut u8, len);
+        *ptr = ptr.add(len);
+        u64::from_le(value)
+    }
+}
+
+// returns the data length of `values` values, ignoring the padding codes of a partial key group
+pub(crate) fn data_len<A>(
+    backend: Backend,
+    values: usize,
+    keys: &[u8],
+) -> Result<usize, DecodeError>
+where
+    A: Codes,
+{
+    check_keys_len(values, keys.len())?;
+
+    let whole = values / 8 * 3;
+    let mut len = A::keys_data_len(backend, &keys[..whole]);
+
+    let remaining = values % 8;
+    if remaining != 0 {
+        let group = &keys[whole..whole + 3];
+        let key = group[0] as usize | (group[1] as usize) << 8 | (group[2] as usize) << 16;
+        let key = key & ((1 << (3 * remaining)) - 1);
+        len += A::LENGTH[key & ((1 << 12) - 1)] as usize;
+        len += A::LENGTH[key >> 12] as usize;
+        len -= (8 - remaining) * A::LENGTHS[0] as usize;
+    }
+
+    Ok(len)
+}
+
+/// Like `try_compressed_data_len`, for buffers encoded with `alphabet`.
+pub fn compressed_data_len_with_alphabet(
+    values: usize,
+    keys: &[u8],
+    alphabet: Alphabet,
+) -> Result<usize, DecodeError> {
+    Engine::new().compressed_data_len_with_alphabet(values, keys, alphabet)
+}
+
+/// Like `encode`, but maps codes to data lengths according to `alphabet`.
+///
+/// The buffer can only be decoded with the same alphabet.
+///
+/// # Panics
+///
+/// Panics if `buf` is shorter than `max_compressed_len(input.len())`.
+pub fn encode_with_alphabet(input: &[u64], buf: &mut [u8], alphabet: Alphabet) -> usize {
+    Engine::new().encode_with_alphabet(input, buf, alphabet)
+}
+
+/// Like `try_decode`, for buffers encoded with `alphabet`.
+pub fn decode_with_alphabet(
+    output: &mut [u64],
+    buf: &[u8],
+    alphabet: Alphabet,
+) -> Result<usize, DecodeError> {
+    Engine::new().decode_with_alphabet(output, buf, alphabet)
+}
+
+impl Engine {
+    /// Like the crate's `compressed_data_len_with_alphabet`, using this engine's backend.
+    pub fn compressed_data_len_with_alphabet(
+        &self,
+        values: usize,
+        keys: &[u8],
+        alphabet: Alphabet,
+    ) -> Result<usize, DecodeError> {
+        match alphabet {
+            Alphabet::Standard => data_len::<Standard>(self.backend(), values, keys),
+            Alphabet::Sparse => data_len::<Sparse>(self.backend(), values, keys),
+        }
+    }
+
+    /// Like the crate's `encode_with_alphabet`, using this engine's backend.
+    pub fn encode_with_alphabet(&self, input: &[u64], buf: &mut [u8], alphabet: Alphabet) -> usize {
+        assert!(buf.len() >= max_compressed_len(input.len()));
+        let keys_len = keys_len(input.len());
+        let (keys, data) = buf.split_at_mut(keys_len);
+
+        let written = unsafe {
+            match alphabet {
+                Alphabet::Standard => self.backend().encode_with(input, keys, data, &mut Plain),
+                Alphabet::Sparse => encode_sparse(self.backend(), input, keys, data),
+            }
+        };
+
+        keys_len + written
+    }
+
+    /// Like the crate's `decode_with_alphabet`, using this engine's backend.
+    pub fn decode_with_alphabet(
+        &self,
+        output: &mut [u64],
+        buf: &[u8],
+        alphabet: Alphabet,
+    ) -> Result<usize, DecodeError> {
+        let keys_len = keys_len(output.len());
+        let data_len = self.compressed_data_len_with_alphabet(output.len(), buf, alphabet)?;
+        let data = &buf[keys_len..];
+        if data.len() < data_len {
+            return Err(DecodeError::DataTruncated {
+                needed: data_len,
+                available: data.len(),
+            });
+        }
+
+        let (keys, data) = (&buf[..keys_len], &data[..data_len]);
+        let read = unsafe {
+            match alphabet {
+                Alphabet::Standard => self.backend().decode_with(output, keys, data, &mut Plain),
+                Alphabet::Sparse => decode_sparse(self.backend(), output, keys, data),
+            }
+        };
+        debug_assert_eq!(read, data_len);
+        Ok(keys_len + read)
+    }
+}
+
+unsafe fn encode_sparse(
+    backend: Backend,
+    input: &[u64],
+    keys: &mut [u8],
+    data: &mut [u8],
+) -> usize {
+    match backend {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Backend::Avx2 => avx2::encode_avx_alphabet::<Sparse, _>(input, keys, data, &mut Plain),
+        _ => encode_scalar_alphabet::<Sparse, _>(input, keys, data, &mut Plain),
+    }
+}
+
+unsafe fn decode_sparse(backend: Backend, output: &mut [u64], keys: &[u8], data: &[u8]) -> usize {
+    match backend {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Backend::Avx2 => avx2::decode_avx_alphabet::<Sparse, _>(output, keys, data, &mut Plain),
+        _ => decode_scalar_alphabet::<Sparse, _>(output, keys, data, &mut Plain),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sparse_values() -> Vec<u64> {
+        (0..4090u64)
+            .map(|v| match v % 5 {
+                0..=2 => 0,
+                3 => v,
+                _ => v.wrapping_mul(0x0123_4567_89ab_cdef) >> (v % 8 * 8),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let values = sparse_values();
+        let mut buf = vec![0; max_compressed_len(values.len())];
+        for &alphabet in &[Alphabet::Standard, Alphabet::Sparse] {
+            for len in (0..100).chain(4000..4090) {
+                let values = &values[..len];
+                let written = encode_with_alphabet(values, &mut buf, alphabet);
+
+                let mut out = vec![0; len];
+                assert_eq!(
+                    decode_with_alphabet(&mut out, &buf[..written], alphabet),
+                    Ok(written)
+                );
+                assert_eq!(values, &out[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn sparse_is_smaller() {
+        let values = sparse_values();
+        let mut buf = vec![0; max_compressed_len(values.len())];
+        let standard = encode_with_alphabet(&values, &mut buf, Alphabet::Standard);
+        let sparse = encode_with_alphabet(&values, &mut buf, Alphabet::Sparse);
+        assert!(sparse + values.len() / 2 <= standard);
+
+        let zeros = vec![0; 64];
+        assert_eq!(
+            encode_with_alphabet(&zeros, &mut buf, Alphabet::Sparse),
+            keys_len(64)
+        );
+    }
+
+    #[test]
+    fn sparse_lengths() {
+        let mut buf = [0; 8];
+        for (i, &len) in Sparse::LENGTHS.iter().enumerate() {
+            let value = if i == 0 { 0 } else { 1 << (8 * (len - 1)) };
+            let mut ptr = buf.as_mut_ptr();
+            let code = unsafe { Sparse::encode_single(value, &mut ptr) };
+            assert_eq!(Sparse::LENGTHS[code as usize], len);
+            assert_eq!(ptr as usize - buf.as_ptr() as usize, len as usize);
+        }
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn match_scalar() {
+        let values = sparse_values();
+        unsafe {
+            let mut keys1 = vec![0; keys_len(values.len())];
+            let mut data1 = vec![0; values.len() * 8];
+            let written1 =
+                encode_scalar_alphabet::<Sparse, _>(&values, &mut keys1, &mut data1, &mut Plain);
+
+            let mut keys2 = vec![0; keys_len(values.len())];
+            let mut data2 = vec![0; values.len() * 8];
+            let written2 =
+                avx2::encode_avx_alphabet::<Sparse, _>(&values, &mut keys2, &mut data2, &mut Plain);
+
+            assert_eq!(keys1, keys2);
+            assert_eq!(data1, data2);
+            assert_eq!(written1, written2);
+
+            let data = &data1[..written1];
+            let mut out1 = vec![0; values.len()];
+            decode_scalar_alphabet::<Sparse, _>(&mut out1, &keys1, data, &mut Plain);
+            let mut out2 = vec![0; values.len()];
+            avx2::decode_avx_alphabet::<Sparse, _>(&mut out2, &keys1, data, &mut Plain);
+            assert_eq!(values, out1);
+            assert_eq!(values, out2);
+        }
+    }
+}
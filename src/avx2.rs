@@ -0,0 +1,577 @@
+//! The AVX2 backend.
+//!
+//! Each 4 value half of a key group is handled with a single 256 bit shuffle, using tables indexed
+//! by the 12 bits of key for the block.
+#[cfg(test)]
+use std::cell::Cell;
+use std::ptr;
+use std::slice;
+
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+use crate::alphabet::{Codes, Standard};
+#[cfg(not(feature = "compact-tables"))]
+use crate::tables::Shuffle;
+use crate::transform::{Plain, Transform};
+use crate::{
+    decode_scalar_alphabet, encode_scalar_alphabet, encoded_len_single, keys_data_len_scalar,
+    keys_len,
+};
+
+#[cfg(test)]
+thread_local! {
+    // the number of calls into the AVX2 kernels made by this thread, so tests can check that other
+    // backends never reach them
+    pub(crate) static CALLS: Cell<usize> = const { Cell::new(0) };
+}
+
+#[cfg(test)]
+pub(crate) fn record_call() {
+    CALLS.with(|calls| calls.set(calls.get() + 1));
+}
+
+#[cfg(not(feature = "compact-tables"))]
+impl Shuffle {
+    #[target_feature(enable = "avx2")]
+    #[inline]
+    unsafe fn load(&self) -> __m256i {
+        _mm256_load_si256(self.0.as_ptr() as *const __m256i)
+    }
+}
+
+// returns the 12 bit key and encoded length of a block of 4 values
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn classify_block_avx<A>(value: __m256i) -> (u32, usize)
+where
+    A: Codes,
+{
+    classify_lane_codes::<A>(lane_codes_avx::<A>(value))
+}
+
+// returns the lane code of each value in its most significant byte
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn lane_codes_avx<A>(value: __m256i) -> __m256i
+where
+    A: Codes,
+{
+    // turn each byte into a 0 or 1 based on it being nonzero
+    let ones = _mm256_set1_epi8(1);
+    let mins = _mm256_min_epu8(value, ones);
+
+    // collect those bits into the high byte of each 32 bit part, most significant byte first
+    // the multiply acts like a multi-bit shift
+    let shifts = _mm256_set1_epi32(1 | 1 << 9 | 1 << 18 | 1 << 27);
+    let bytemaps = _mm256_mullo_epi32(mins, shifts);
+
+    // use that as the mask vector to select the lane code
+    // first the low half
+    let low_lane_codes =
+        _mm256_broadcastsi128_si256(_mm_loadu_si128(A::LOW_LANE_CODES.as_ptr() as *const __m128i));
+    let low_shuffled_codes = _mm256_shuffle_epi8(low_lane_codes, bytemaps);
+    let low_shifted_codes = _mm256_slli_epi64(low_shuffled_codes, 32);
+
+    // now the high half
+    let high_lane_codes = _mm256_broadcastsi128_si256(_mm_loadu_si128(
+        A::HIGH_LANE_CODES.as_ptr() as *const __m128i
+    ));
+    let high_shuffled_codes = _mm256_shuffle_epi8(high_lane_codes, bytemaps);
+
+    // overlay and take the max of the low and high codes
+    _mm256_max_epu8(low_shifted_codes, high_shuffled_codes)
+}
+
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn classify_lane_codes<A>(lane_codes: __m256i) -> (u32, usize)
+where
+    A: Codes,
+{
+    // now gather three copies of the lane codes from each lane
+    #[rustfmt::skip]
+    let gather_high = _mm256_setr_epi8(
+        -1, 15, 7, -1, -1, -1, -1, -1, -1, -1, 15, 7, -1, -1, -1, -1,
+        7, -1, -1, -1, -1, 15, 7, -1, 15, 7, -1, -1, -1, -1, -1, -1,
+    );
+    let shuffled_codes = _mm256_shuffle_epi8(lane_codes, gather_high);
+    let permuted = _mm256_permute4x64_epi64(shuffled_codes, 0b00001110);
+    let high_bytes = _mm256_or_si256(shuffled_codes, permuted);
+
+    // we're going to concatenate and sum the lane codes at the same time
+    let concat_low = 1 << 8 | 1 << 19 | 1 << 30;
+    let concat_high = 1 << 6 | 1 << 17;
+    let sum = 1 | 1 << 8 | 1 << 16 | 1 << 24;
+    let aggregators = _mm256_setr_epi32(concat_low, concat_high, sum, 0, 0, 0, 0, 0);
+
+    let code_and_length = _mm256_mullo_epi32(high_bytes, aggregators);
+
+    let code_low = _mm256_extract_epi8(code_and_length, 3) as u8;
+    let code_high = _mm256_extract_epi8(code_and_length, 7) as u8 & 0xf;
+    let code = (code_low as u32) | ((code_high as u32) << 8);
+    // the sum only gives the length when every code is one less than its length
+    let length = if A::LENGTHS == Standard::LENGTHS {
+        _mm256_extract_epi8(code_and_length, 11) as usize + 4
+    } else {
+        A::LENGTH[code as usize] as usize
+    };
+
+    (code, length)
+}
+
+#[cfg(not(feature = "compact-tables"))]
+#[target_feature(enable = "avx2")]
+unsafe fn encode_block_avx<A>(ptr: &mut *mut u8, value: __m256i) -> u32
+where
+    A: Codes,
+{
+    let (code, length) = classify_block_avx::<A>(value);
+
+    let shuffle1 = A::ENCODE_SHUFFLE_1[code as usize].load();
+    let data1 = _mm256_shuffle_epi8(value, shuffle1);
+
+    let shuffle2 = A::ENCODE_SHUFFLE_2[code as usize].load();
+    let shuffled2 = _mm256_shuffle_epi8(value, shuffle2);
+    let data2 = _mm256_permute4x64_epi64(shuffled2, 0b00001110);
+
+    let data = _mm256_or_si256(data1, data2);
+    _mm256_storeu_si256(*ptr as *mut __m256i, data);
+    *ptr = ptr.add(length);
+
+    code
+}
+
+// packs each 128 bit lane with a 64 entry pair table rather than moving bytes across lanes with
+// the 4096 entry tables, at the cost of a second store
+#[cfg(feature = "compact-tables")]
+#[target_feature(enable = "avx2")]
+unsafe fn encode_block_avx<A>(ptr: &mut *mut u8, value: __m256i) -> u32
+where
+    A: Codes,
+{
+    let (code, length) = classify_block_avx::<A>(value);
+
+    let shuffle = _mm256_inserti128_si256(
+        _mm256_castsi128_si256(A::PAIR_ENCODE_SHUFFLE[code as usize & 0x3f].load()),
+        A::PAIR_ENCODE_SHUFFLE[code as usize >> 6].load(),
+        1,
+    );
+    let data = _mm256_shuffle_epi8(value, shuffle);
+
+    _mm_storeu_si128(*ptr as *mut __m128i, _mm256_castsi256_si128(data));
+    let low_length = pair_len::<A>(code & 0x3f);
+    _mm_storeu_si128(
+        ptr.add(low_length) as *mut __m128i,
+        _mm256_extracti128_si256(data, 1),
+    );
+    *ptr = ptr.add(length);
+
+    code
+}
+
+#[cfg(feature = "compact-tables")]
+#[inline]
+fn pair_len<A>(code: u32) -> usize
+where
+    A: Codes,
+{
+    A::LENGTHS[code as usize & 0b111] as usize + A::LENGTHS[code as usize >> 3] as usize
+}
+
+// builds the shuffle that packs each 128 bit lane from the lengths of its two values rather than
+// looking it up in a table, at the cost of a second store
+#[target_feature(enable = "avx2")]
+unsafe fn encode_block_table_free_avx<A>(ptr: &mut *mut u8, value: __m256i) -> u32
+where
+    A: Codes,
+{
+    let lane_codes = lane_codes_avx::<A>(value);
+    let (code, length) = classify_lane_codes::<A>(lane_codes);
+
+    let mut lut = [0; 16];
+    lut[..8].copy_from_slice(&A::LENGTHS);
+    let lut = _mm256_broadcastsi128_si256(_mm_loadu_si128(lut.as_ptr() as *const __m128i));
+    let lengths = _mm256_shuffle_epi8(lut, lane_codes);
+
+    // spread the lengths of the first and second value of each lane across it
+    let first_lengths = _mm256_shuffle_epi8(lengths, _mm256_set1_epi8(7));
+    let second_lengths = _mm256_shuffle_epi8(lengths, _mm256_set1_epi8(15));
+
+    // output byte j comes from byte j of the first value while j is less than its length, and
+    // from byte j - length of the second value after that. Bytes past the end of the second
+    // value are garbage, but they're overwritten or past the end of the data.
+    #[rustfmt::skip]
+    let positions = _mm256_setr_epi8(
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+    );
+    let in_first = _mm256_cmpgt_epi8(first_lengths, positions);
+    let offsets = _mm256_sub_epi8(_mm256_set1_epi8(8), first_lengths);
+    let shuffle = _mm256_add_epi8(positions, _mm256_andnot_si256(in_first, offsets));
+    let data = _mm256_shuffle_epi8(value, shuffle);
+
+    let low_length = _mm256_extract_epi8(_mm256_add_epi8(first_lengths, second_lengths), 0);
+    _mm_storeu_si128(*ptr as *mut __m128i, _mm256_castsi256_si128(data));
+    _mm_storeu_si128(
+        ptr.add(low_length as usize) as *mut __m128i,
+        _mm256_extracti128_si256(data, 1),
+    );
+    *ptr = ptr.add(length);
+
+    code
+}
+
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn encode_block<A, const TABLE_FREE: bool>(ptr: &mut *mut u8, value: __m256i) -> u32
+where
+    A: Codes,
+{
+    if TABLE_FREE {
+        encode_block_table_free_avx::<A>(ptr, value)
+    } else {
+        encode_block_avx::<A>(ptr, value)
+    }
+}
+
+/// Encodes `input` into separate key and data buffers, returning the number of data bytes written.
+///
+/// # Safety
+///
+/// `keys` must be at least `keys_len(input.len())` bytes long, and `data` must be large enough to
+/// hold the encoded data. The CPU must support AVX2.
+#[target_feature(enable = "avx2")]
+pub unsafe fn encode_avx(input: &[u64], keys: &mut [u8], data: &mut [u8]) -> usize {
+    encode_avx_with(input, keys, data, &mut Plain)
+}
+
+#[target_feature(enable = "avx2")]
+pub unsafe fn encode_avx_with<T>(
+    input: &[u64],
+    keys: &mut [u8],
+    data: &mut [u8],
+    transform: &mut T,
+) -> usize
+where
+    T: Transform,
+{
+    encode_avx_alphabet::<Standard, T>(input, keys, data, transform)
+}
+
+/// Like `encode_avx`, but builds the shuffles which pack each block from the lengths of its values
+/// instead of loading them from a table.
+///
+/// # Safety
+///
+/// `keys` must be at least `keys_len(input.len())` bytes long, and `data` must be large enough to
+/// hold the encoded data. The CPU must support AVX2.
+#[target_feature(enable = "avx2")]
+pub unsafe fn encode_avx_table_free(input: &[u64], keys: &mut [u8], data: &mut [u8]) -> usize {
+    encode_avx_impl::<Standard, Plain, true>(input, keys, data, &mut Plain)
+}
+
+#[target_feature(enable = "avx2")]
+pub unsafe fn encode_avx_alphabet<A, T>(
+    input: &[u64],
+    keys: &mut [u8],
+    data: &mut [u8],
+    transform: &mut T,
+) -> usize
+where
+    A: Codes,
+    T: Transform,
+{
+    encode_avx_impl::<A, T, false>(input, keys, data, transform)
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn encode_avx_impl<A, T, const TABLE_FREE: bool>(
+    input: &[u64],
+    keys: &mut [u8],
+    data: &mut [u8],
+    transform: &mut T,
+) -> usize
+where
+    A: Codes,
+    T: Transform,
+{
+    debug_assert!(keys.len() >= keys_len(input.len()));
+    #[cfg(test)]
+    record_call();
+
+    let mut inputptr = input.as_ptr();
+    let mut keyptr = keys.as_mut_ptr();
+    let mut dataptr = data.as_mut_ptr();
+    let dataend = data.as_ptr().add(data.len());
+
+    // each block stores a full 32 bytes no matter how many of them it actually uses, so a key
+    // group can write up to 64 bytes. Once there's less than that left in `data` we have to
+    // switch over to the scalar path, which only writes what it needs to.
+    let groups = input.len() / 8;
+    let mut count = 0;
+    while count < groups && dataend as usize - dataptr as usize >= 64 {
+        count += 1;
+
+        let data = _mm256_loadu_si256(inputptr as *const __m256i);
+        inputptr = inputptr.add(4);
+        let code_low = encode_block::<A, TABLE_FREE>(&mut dataptr, transform.encode_avx(data));
+
+        let data = _mm256_loadu_si256(inputptr as *const __m256i);
+        inputptr = inputptr.add(4);
+        let code_high = encode_block::<A, TABLE_FREE>(&mut dataptr, transform.encode_avx(data));
+
+        let code = (code_low as u32) | ((code_high as u32) << 12);
+        ptr::copy_nonoverlapping(&code as *const u32 as *const u8, keyptr, 3);
+        keyptr = keyptr.add(3);
+    }
+
+    let written = dataptr as usize - data.as_ptr() as usize;
+    let input = slice::from_raw_parts(inputptr, input.len() - count * 8);
+    let keys = slice::from_raw_parts_mut(
+        keyptr,
+        keys.as_ptr().add(keys.len()) as usize - keyptr as usize,
+    );
+    let data = slice::from_raw_parts_mut(
+        dataptr,
+        data.as_ptr().add(data.len()) as usize - dataptr as usize,
+    );
+
+    encode_scalar_alphabet::<A, T>(input, keys, data, transform) + written
+}
+
+#[cfg(not(feature = "compact-tables"))]
+#[target_feature(enable = "avx2")]
+unsafe fn decode_block_avx<A>(ptr: &mut *const u8, code: u32) -> __m256i
+where
+    A: Codes,
+{
+    let len = A::LENGTH[code as usize];
+
+    let data = _mm256_loadu_si256(*ptr as *const __m256i);
+
+    let shuffle1 = A::DECODE_SHUFFLE_1[code as usize].load();
+    let data1 = _mm256_shuffle_epi8(data, shuffle1);
+
+    let shuffle2 = A::DECODE_SHUFFLE_2[code as usize].load();
+    let shuffled2 = _mm256_shuffle_epi8(data, shuffle2);
+    let data2 = _mm256_permute4x64_epi64(shuffled2, 0b01001111);
+
+    let data = _mm256_or_si256(data1, data2);
+
+    *ptr = ptr.add(len as usize);
+    data
+}
+
+// loads each pair into its own 128 bit lane so that it can be unpacked with a 64 entry pair table
+// rather than the 4096 entry tables
+#[cfg(feature = "compact-tables")]
+#[target_feature(enable = "avx2")]
+unsafe fn decode_block_avx<A>(ptr: &mut *const u8, code: u32) -> __m256i
+where
+    A: Codes,
+{
+    let low = _mm_loadu_si128(*ptr as *const __m128i);
+    let high = _mm_loadu_si128(ptr.add(pair_len::<A>(code & 0x3f)) as *const __m128i);
+    let data = _mm256_inserti128_si256(_mm256_castsi128_si256(low), high, 1);
+
+    let shuffle = _mm256_inserti128_si256(
+        _mm256_castsi128_si256(A::PAIR_DECODE_SHUFFLE[code as usize & 0x3f].load()),
+        A::PAIR_DECODE_SHUFFLE[code as usize >> 6].load(),
+        1,
+    );
+    let data = _mm256_shuffle_epi8(data, shuffle);
+
+    *ptr = ptr.add(A::LENGTH[code as usize] as usize);
+    data
+}
+
+/// Returns the number of data bytes `encode_avx` writes for `input`, without encoding it.
+///
+/// # Safety
+///
+/// The CPU must support AVX2.
+#[target_feature(enable = "avx2")]
+pub unsafe fn encoded_data_len_avx(input: &[u64]) -> usize {
+    #[cfg(test)]
+    record_call();
+    let mut blocks = input.chunks_exact(4);
+    let mut len = 0;
+    for block in &mut blocks {
+        let value = _mm256_loadu_si256(block.as_ptr() as *const __m256i);
+        len += classify_block_avx::<Standard>(value).1;
+    }
+
+    len + blocks
+        .remainder()
+        .iter()
+        .map(|&v| encoded_len_single(v))
+        .sum::<usize>()
+}
+
+// masks of the bits of a 256 bit vector whose index is 0, 1 or 2 mod 3
+const fn phase_mask(phase: usize) -> [u8; 32] {
+    let mut mask = [0; 32];
+    let mut i = 0;
+    while i < 256 {
+        if i % 3 == phase {
+            mask[i / 8] |= 1 << (i % 8);
+        }
+        i += 1;
+    }
+    mask
+}
+
+static PHASE_MASKS: [[u8; 32]; 3] = [phase_mask(0), phase_mask(1), phase_mask(2)];
+
+// returns the number of set bits in each byte
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn popcount_epi8(v: __m256i) -> __m256i {
+    #[rustfmt::skip]
+    let counts = _mm256_setr_epi8(
+        0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4,
+        0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4,
+    );
+    let nibble = _mm256_set1_epi8(0x0f);
+    let low = _mm256_shuffle_epi8(counts, _mm256_and_si256(v, nibble));
+    let high = _mm256_shuffle_epi8(counts, _mm256_and_si256(_mm256_srli_epi16(v, 4), nibble));
+    _mm256_add_epi8(low, high)
+}
+
+// returns, for each byte, the sum of the weights of its set bits where the bits selected by `m1`,
+// `m2` and `m4` have weight 1, 2 and 4 respectively
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn weighted_popcount(v: __m256i, m1: __m256i, m2: __m256i, m4: __m256i) -> __m256i {
+    // each byte count is at most 8, so the 16 bit shifts never carry between bytes
+    let c1 = popcount_epi8(_mm256_and_si256(v, m1));
+    let c2 = popcount_epi8(_mm256_and_si256(v, m2));
+    let c4 = popcount_epi8(_mm256_and_si256(v, m4));
+    _mm256_add_epi8(
+        c1,
+        _mm256_add_epi8(_mm256_slli_epi16(c2, 1), _mm256_slli_epi16(c4, 2)),
+    )
+}
+
+/// Returns the total data length of the key groups in `keys`.
+///
+/// The length of a group is 8 plus the sum of its codes, and since key groups are 24 bits long,
+/// each bit of the keys contributes 1, 2 or 4 to that sum depending only on its index mod 3. The
+/// sum is computed with weighted popcounts over 96 bytes (32 key groups) at a time.
+///
+/// # Safety
+///
+/// The CPU must support AVX2.
+#[target_feature(enable = "avx2")]
+pub unsafe fn keys_data_len_avx(keys: &[u8]) -> usize {
+    #[cfg(test)]
+    record_call();
+    let a0 = _mm256_loadu_si256(PHASE_MASKS[0].as_ptr() as *const __m256i);
+    let a1 = _mm256_loadu_si256(PHASE_MASKS[1].as_ptr() as *const __m256i);
+    let a2 = _mm256_loadu_si256(PHASE_MASKS[2].as_ptr() as *const __m256i);
+
+    let mut chunks = keys.chunks_exact(96);
+    let mut sums = _mm256_setzero_si256();
+    for chunk in &mut chunks {
+        let ptr = chunk.as_ptr() as *const __m256i;
+        // 256 is 1 mod 3, so the weights rotate between the three vectors
+        let w0 = weighted_popcount(_mm256_loadu_si256(ptr), a0, a1, a2);
+        let w1 = weighted_popcount(_mm256_loadu_si256(ptr.add(1)), a2, a0, a1);
+        let w2 = weighted_popcount(_mm256_loadu_si256(ptr.add(2)), a1, a2, a0);
+
+        // a byte holds at most 3 bits of weight 4, 3 of weight 2 and 2 of weight 1, so each
+        // weighted popcount byte is at most 20 and each byte of the sum at most 60
+        let w = _mm256_add_epi8(w0, _mm256_add_epi8(w1, w2));
+        sums = _mm256_add_epi64(sums, _mm256_sad_epu8(w, _mm256_setzero_si256()));
+    }
+
+    let mut lanes = [0u64; 4];
+    _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, sums);
+    let codes = lanes.iter().sum::<u64>() as usize;
+    let groups = (keys.len() - chunks.remainder().len()) / 3;
+
+    codes + groups * 8 + keys_data_len_scalar::<Standard>(chunks.remainder())
+}
+
+/// Decodes `output.len()` values from separate key and data buffers, returning the number of data
+/// bytes read.
+///
+/// # Safety
+///
+/// `keys` must be at least `keys_len(output.len())` bytes long, and `data` must hold all of the
+/// encoded data. The CPU must support AVX2.
+#[target_feature(enable = "avx2")]
+pub unsafe fn decode_avx(output: &mut [u64], keys: &[u8], data: &[u8]) -> usize {
+    decode_avx_with(output, keys, data, &mut Plain)
+}
+
+#[target_feature(enable = "avx2")]
+pub unsafe fn decode_avx_with<T>(
+    output: &mut [u64],
+    keys: &[u8],
+    data: &[u8],
+    transform: &mut T,
+) -> usize
+where
+    T: Transform,
+{
+    decode_avx_alphabet::<Standard, T>(output, keys, data, transform)
+}
+
+#[target_feature(enable = "avx2")]
+pub unsafe fn decode_avx_alphabet<A, T>(
+    output: &mut [u64],
+    keys: &[u8],
+    data: &[u8],
+    transform: &mut T,
+) -> usize
+where
+    A: Codes,
+    T: Transform,
+{
+    debug_assert!(keys.len() >= keys_len(output.len()));
+    #[cfg(test)]
+    record_call();
+
+    let mut outptr = output.as_mut_ptr();
+    let mut keyptr = keys.as_ptr();
+    let mut dataptr = data.as_ptr();
+    let dataend = data.as_ptr().add(data.len());
+
+    // each block loads a full 32 bytes no matter how many of them it actually uses, and a block
+    // starts at most 32 bytes into its key group, so a key group can read up to 64 bytes. Values
+    // can take up no data at all, so there's no telling how many of them fit in that, and we
+    // have to check what's left in `data` rather than count values.
+    let groups = output.len() / 8;
+    let mut count = 0;
+    while count < groups && dataend as usize - dataptr as usize >= 64 {
+        count += 1;
+
+        let mut key = 0u32;
+        ptr::copy_nonoverlapping(keyptr, &mut key as *mut u32 as *mut u8, 3);
+        keyptr = keyptr.add(3);
+
+        let values = decode_block_avx::<A>(&mut dataptr, key & ((1 << 12) - 1));
+        let values = transform.decode_avx(values);
+        _mm256_storeu_si256(outptr as *mut __m256i, values);
+        outptr = outptr.add(4);
+
+        let values = decode_block_avx::<A>(&mut dataptr, key >> 12);
+        let values = transform.decode_avx(values);
+        _mm256_storeu_si256(outptr as *mut __m256i, values);
+        outptr = outptr.add(4);
+    }
+
+    let read = dataptr as usize - data.as_ptr() as usize;
+    let output = slice::from_raw_parts_mut(outptr, output.len() - count * 8);
+    let keys = slice::from_raw_parts(
+        keyptr,
+        keys.as_ptr().add(keys.len()) as usize - keyptr as usize,
+    );
+    let data = slice::from_raw_parts(dataptr, dataend as usize - dataptr as usize);
+
+    decode_scalar_alphabet::<A, T>(output, keys, data, transform) + read
+}
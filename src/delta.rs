@@ -0,0 +1,156 @@
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+use crate::engine::Engine;
+use crate::transform::Transform;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::transform::{prefix_sum_avx, shift_in_avx};
+use crate::{decode_with, encode_with};
+
+pub struct Delta {
+    prev: u64,
+}
+
+impl Delta {
+    pub fn new(initial: u64) -> Delta {
+        Delta { prev: initial }
+    }
+}
+
+impl Transform for Delta {
+    #[inline]
+    fn encode(&mut self, value: u64) -> u64 {
+        let delta = value.wrapping_sub(self.prev);
+        self.prev = value;
+        delta
+    }
+
+    #[inline]
+    fn decode(&mut self, delta: u64) -> u64 {
+        self.prev = self.prev.wrapping_add(delta);
+        self.prev
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx2")]
+    #[inline]
+    unsafe fn encode_avx(&mut self, values: __m256i) -> __m256i {
+        let prev = _mm256_set1_epi64x(self.prev as i64);
+        self.prev = last_lane(values);
+        _mm256_sub_epi64(values, shift_in_avx(values, prev))
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx2")]
+    #[inline]
+    unsafe fn decode_avx(&mut self, deltas: __m256i) -> __m256i {
+        let prev = _mm256_set1_epi64x(self.prev as i64);
+        let values = prefix_sum_avx(deltas, prev);
+        self.prev = last_lane(values);
+        values
+    }
+}
+
+// returns the last lane of `values`; `_mm256_extract_epi64` would do, but only exists on x86_64
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn last_lane(values: __m256i) -> u64 {
+    let mut lanes = [0u64; 4];
+    _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, values);
+    lanes[3]
+}
+
+/// Encodes the differences between consecutive values of `input` into `buf`.
+///
+/// The first value is encoded relative to `initial`. This is much more compact than `encode` for
+/// sorted inputs, though any input will round trip since differences wrap around.
+pub fn encode_delta(input: &[u64], initial: u64, buf: &mut [u8]) -> usize {
+    Engine::new().encode_delta(input, initial, buf)
+}
+
+/// Decodes values encoded by `encode_delta` with the same `initial` value.
+pub fn decode_delta(output: &mut [u64], initial: u64, buf: &[u8]) -> usize {
+    Engine::new().decode_delta(output, initial, buf)
+}
+
+impl Engine {
+    /// Like the crate's `encode_delta`, using this engine's backend.
+    pub fn encode_delta(&self, input: &[u64], initial: u64, buf: &mut [u8]) -> usize {
+        encode_with(self.backend(), input, buf, &mut Delta::new(initial))
+    }
+
+    /// Like the crate's `decode_delta`, using this engine's backend.
+    pub fn decode_delta(&self, output: &mut [u64], initial: u64, buf: &[u8]) -> usize {
+        decode_with(self.backend(), output, buf, &mut Delta::new(initial))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::max_compressed_len;
+
+    fn sorted_values() -> Vec<u64> {
+        (0..4090u64)
+            .map(|v| 1_500_000_000_000 + v * 1000 + v % 7 * 100)
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let values = sorted_values();
+        let mut buf = vec![0; max_compressed_len(values.len())];
+        let written = encode_delta(&values, 1_500_000_000_000, &mut buf);
+        assert!(written < values.len() * 3);
+
+        let mut out = vec![0; values.len()];
+        decode_delta(&mut out, 1_500_000_000_000, &buf[..written]);
+        assert_eq!(values, out);
+    }
+
+    #[test]
+    fn unsorted_round_trip() {
+        let values = (0..997)
+            .map(|v| v * (u64::MAX / 997))
+            .rev()
+            .collect::<Vec<_>>();
+        let mut buf = vec![0; max_compressed_len(values.len())];
+        let written = encode_delta(&values, 5, &mut buf);
+
+        let mut out = vec![0; values.len()];
+        decode_delta(&mut out, 5, &buf[..written]);
+        assert_eq!(values, out);
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn match_scalar() {
+        use crate::avx2::{decode_avx_with, encode_avx_with};
+        use crate::{decode_scalar_with, encode_scalar_with, keys_len};
+
+        unsafe {
+            let values = sorted_values();
+            let mut keys1 = vec![0; keys_len(values.len())];
+            let mut data1 = vec![0; values.len() * 8];
+            let written1 = encode_scalar_with(&values, &mut keys1, &mut data1, &mut Delta::new(3));
+
+            let mut keys2 = vec![0; keys_len(values.len())];
+            let mut data2 = vec![0; values.len() * 8];
+            let written2 = encode_avx_with(&values, &mut keys2, &mut data2, &mut Delta::new(3));
+
+            assert_eq!(keys1, keys2);
+            assert_eq!(data1, data2);
+            assert_eq!(written1, written2);
+
+            let mut out1 = vec![0; values.len()];
+            decode_scalar_with(&mut out1, &keys1, &data1, &mut Delta::new(3));
+            let mut out2 = vec![0; values.len()];
+            decode_avx_with(&mut out2, &keys1, &data1, &mut Delta::new(3));
+            assert_eq!(values, out1);
+            assert_eq!(values, out2);
+        }
+    }
+}
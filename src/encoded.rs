@@ -0,0 +1,478 @@
+use std::iter::FromIterator;
+use std::ops::Range;
+
+use crate::engine::Backend;
+use crate::index::{code_len, decode_from, read_key, read_value, seek_from};
+use crate::transform::Plain;
+use crate::{check_keys_len, keys_len, try_compressed_data_len, DecodeError};
+
+// the number of values buffered at a time while extending
+const CHUNK_LEN: usize = 1024;
+
+/// An owned buffer of encoded values which tracks its length and layout.
+///
+/// Values are encoded and decoded with the default engine, `Engine::new()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EncodedU64 {
+    count: usize,
+    keys: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl EncodedU64 {
+    /// Creates a new, empty buffer.
+    pub fn new() -> EncodedU64 {
+        EncodedU64::default()
+    }
+
+    /// Encodes `input` into a new buffer.
+    pub fn from_slice(input: &[u64]) -> EncodedU64 {
+        let mut encoded = EncodedU64::new();
+        encoded.append(input);
+        encoded
+    }
+
+    /// Returns a borrowed view of the buffer.
+    pub fn as_encoded_ref(&self) -> EncodedU64Ref<'_> {
+        EncodedU64Ref {
+            count: self.count,
+            keys: &self.keys,
+            data: &self.data,
+        }
+    }
+
+    /// Returns the number of values in the buffer.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Returns `true` if the buffer holds no values.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the keys section of the buffer.
+    pub fn keys(&self) -> &[u8] {
+        &self.keys
+    }
+
+    /// Returns the data section of the buffer.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the `i`th value, or `None` if it is out of bounds.
+    ///
+    /// This is linear in `i`. Use an `Index` for repeated random access.
+    pub fn get(&self, i: usize) -> Option<u64> {
+        self.as_encoded_ref().get(i)
+    }
+
+    /// Decodes the values in `range`, or returns `None` if it is out of bounds.
+    pub fn get_range(&self, range: Range<usize>) -> Option<Vec<u64>> {
+        self.as_encoded_ref().get_range(range)
+    }
+
+    /// Decodes all values into a new `Vec`.
+    pub fn to_vec(&self) -> Vec<u64> {
+        self.as_encoded_ref().to_vec()
+    }
+
+    /// Returns an iterator over the values.
+    pub fn iter(&self) -> EncodedU64Iter<'_> {
+        self.as_encoded_ref().iter()
+    }
+
+    /// Returns the buffer in the layout produced by `encode`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.as_encoded_ref().to_bytes()
+    }
+
+    // appends values to a buffer ending on a key group boundary
+    fn append(&mut self, values: &[u64]) {
+        debug_assert!(self.count.is_multiple_of(8));
+
+        let keys_start = self.keys.len();
+        let data_start = self.data.len();
+        self.keys.resize(keys_start + keys_len(values.len()), 0);
+        self.data.resize(data_start + values.len() * 8, 0);
+
+        let written = unsafe {
+            Backend::current().encode_with(
+                values,
+                &mut self.keys[keys_start..],
+                &mut self.data[data_start..],
+                &mut Plain,
+            )
+        };
+        self.data.truncate(data_start + written);
+        self.count += values.len();
+    }
+}
+
+impl FromIterator<u64> for EncodedU64 {
+    fn from_iter<I>(iter: I) -> EncodedU64
+    where
+        I: IntoIterator<Item = u64>,
+    {
+        let mut encoded = EncodedU64::new();
+        encoded.extend(iter);
+        encoded
+    }
+}
+
+impl Extend<u64> for EncodedU64 {
+    fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = u64>,
+    {
+        let mut values = Vec::with_capacity(CHUNK_LEN);
+
+        // a partial final key group has to be encoded again along with the new values
+        let tail = self.count % 8;
+        if tail != 0 {
+            let key = read_key(&self.keys[self.keys.len() - 3..]);
+            let tail_len = (0..tail).map(|i| code_len(key, i)).sum::<usize>();
+
+            values.extend(self.get_range(self.count - tail..self.count).unwrap());
+            self.keys.truncate(self.keys.len() - 3);
+            self.data.truncate(self.data.len() - tail_len);
+            self.count -= tail;
+        }
+
+        for value in iter {
+            values.push(value);
+            if values.len() == CHUNK_LEN {
+                self.append(&values);
+                values.clear();
+            }
+        }
+        self.append(&values);
+    }
+}
+
+impl IntoIterator for EncodedU64 {
+    type Item = u64;
+    type IntoIter = EncodedU64IntoIter;
+
+    fn into_iter(self) -> EncodedU64IntoIter {
+        EncodedU64IntoIter {
+            cursor: Cursor::new(self.count),
+            encoded: self,
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a EncodedU64 {
+    type Item = u64;
+    type IntoIter = EncodedU64Iter<'a>;
+
+    fn into_iter(self) -> EncodedU64Iter<'a> {
+        self.iter()
+    }
+}
+
+impl From<EncodedU64Ref<'_>> for EncodedU64 {
+    fn from(encoded: EncodedU64Ref<'_>) -> EncodedU64 {
+        EncodedU64 {
+            count: encoded.count,
+            keys: encoded.keys.to_vec(),
+            data: encoded.data.to_vec(),
+        }
+    }
+}
+
+/// A borrowed buffer of encoded values which tracks its length and layout.
+///
+/// Values are decoded with the default engine, `Engine::new()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EncodedU64Ref<'a> {
+    count: usize,
+    keys: &'a [u8],
+    data: &'a [u8],
+}
+
+impl<'a> EncodedU64Ref<'a> {
+    /// Creates a view of a buffer produced by `encode` holding `count` values.
+    ///
+    /// Any bytes past the end of the encoded values are ignored.
+    pub fn new(buf: &'a [u8], count: usize) -> Result<EncodedU64Ref<'a>, DecodeError> {
+        let keys_len = check_keys_len(count, buf.len())?;
+        let data_len = try_compressed_data_len(count, buf)?;
+        let (keys, data) = buf.split_at(keys_len);
+        if data.len() < data_len {
+            return Err(DecodeError::DataTruncated {
+                needed: data_len,
+                available: data.len(),
+            });
+        }
+
+        Ok(EncodedU64Ref {
+            count,
+            keys,
+            data: &data[..data_len],
+        })
+    }
+
+    /// Returns the number of values in the buffer.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Returns `true` if the buffer holds no values.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the keys section of the buffer.
+    pub fn keys(&self) -> &'a [u8] {
+        self.keys
+    }
+
+    /// Returns the data section of the buffer.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Returns the `i`th value, or `None` if it is out of bounds.
+    ///
+    /// This is linear in `i`. Use an `Index` for repeated random access.
+    pub fn get(&self, i: usize) -> Option<u64> {
+        if i >= self.count {
+            return None;
+        }
+
+        let offset = seek_from(self.keys, 0, 0, i);
+        let key = read_key(&self.keys[i / 8 * 3..i / 8 * 3 + 3]);
+        Some(read_value(&self.data[offset..], code_len(key, i % 8)))
+    }
+
+    /// Decodes the values in `range`, or returns `None` if it is out of bounds.
+    pub fn get_range(&self, range: Range<usize>) -> Option<Vec<u64>> {
+        if range.start > range.end || range.end > self.count {
+            return None;
+        }
+
+        let mut out = vec![0; range.len()];
+        if !out.is_empty() {
+            let offset = seek_from(self.keys, 0, 0, range.start);
+            decode_from(
+                Backend::current(),
+                self.keys,
+                self.data,
+                range.start,
+                offset,
+                &mut out,
+            );
+        }
+        Some(out)
+    }
+
+    /// Decodes all values into a new `Vec`.
+    pub fn to_vec(&self) -> Vec<u64> {
+        self.get_range(0..self.count).unwrap()
+    }
+
+    /// Returns an iterator over the values.
+    pub fn iter(&self) -> EncodedU64Iter<'a> {
+        EncodedU64Iter {
+            keys: self.keys,
+            data: self.data,
+            cursor: Cursor::new(self.count),
+        }
+    }
+
+    /// Returns the buffer in the layout produced by `encode`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.keys.len() + self.data.len());
+        buf.extend_from_slice(self.keys);
+        buf.extend_from_slice(self.data);
+        buf
+    }
+}
+
+impl<'a> IntoIterator for EncodedU64Ref<'a> {
+    type Item = u64;
+    type IntoIter = EncodedU64Iter<'a>;
+
+    fn into_iter(self) -> EncodedU64Iter<'a> {
+        self.iter()
+    }
+}
+
+// decodes a buffer a key group at a time
+#[derive(Debug, Clone)]
+struct Cursor {
+    count: usize,
+    next: usize,
+    offset: usize,
+    values: [u64; 8],
+}
+
+impl Cursor {
+    fn new(count: usize) -> Cursor {
+        Cursor {
+            count,
+            next: 0,
+            offset: 0,
+            values: [0; 8],
+        }
+    }
+
+    fn next(&mut self, keys: &[u8], data: &[u8]) -> Option<u64> {
+        if self.next == self.count {
+            return None;
+        }
+
+        if self.next.is_multiple_of(8) {
+            let key = read_key(&keys[self.next / 8 * 3..self.next / 8 * 3 + 3]);
+            let len = (self.count - self.next).min(8);
+            for (i, value) in self.values[..len].iter_mut().enumerate() {
+                let len = code_len(key, i);
+                *value = read_value(&data[self.offset..], len);
+                self.offset += len;
+            }
+        }
+
+        let value = self.values[self.next % 8];
+        self.next += 1;
+        Some(value)
+    }
+
+    fn len(&self) -> usize {
+        self.count - self.next
+    }
+}
+
+/// An iterator over the values of an `EncodedU64Ref` or a borrowed `EncodedU64`.
+#[derive(Debug, Clone)]
+pub struct EncodedU64Iter<'a> {
+    keys: &'a [u8],
+    data: &'a [u8],
+    cursor: Cursor,
+}
+
+impl Iterator for EncodedU64Iter<'_> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        self.cursor.next(self.keys, self.data)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.cursor.len(), Some(self.cursor.len()))
+    }
+}
+
+impl ExactSizeIterator for EncodedU64Iter<'_> {}
+
+/// An iterator over the values of an owned `EncodedU64`.
+#[derive(Debug, Clone)]
+pub struct EncodedU64IntoIter {
+    encoded: EncodedU64,
+    cursor: Cursor,
+}
+
+impl Iterator for EncodedU64IntoIter {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        self.cursor.next(&self.encoded.keys, &self.encoded.data)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.cursor.len(), Some(self.cursor.len()))
+    }
+}
+
+impl ExactSizeIterator for EncodedU64IntoIter {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{encode, max_compressed_len};
+
+    fn values() -> Vec<u64> {
+        (0..3000u64)
+            .map(|v| v.wrapping_mul(0x2545_f491_4f6c_dd1d) >> (v % 9 * 7))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let values = values();
+        let encoded = values.iter().cloned().collect::<EncodedU64>();
+        assert_eq!(encoded.len(), values.len());
+        assert_eq!(encoded.to_vec(), values);
+        assert_eq!(encoded.iter().collect::<Vec<_>>(), values);
+
+        let mut buf = vec![0; max_compressed_len(values.len())];
+        let written = encode(&values, &mut buf);
+        assert_eq!(encoded.to_bytes(), &buf[..written]);
+
+        let encoded_ref = EncodedU64Ref::new(&buf, values.len()).unwrap();
+        assert_eq!(encoded_ref, encoded.as_encoded_ref());
+        assert_eq!(EncodedU64::from(encoded_ref), encoded);
+    }
+
+    #[test]
+    fn extend() {
+        let values = values();
+        let mut encoded = EncodedU64::new();
+        let mut start = 0;
+        for len in (1..).step_by(13) {
+            let end = (start + len).min(values.len());
+            encoded.extend(values[start..end].iter().cloned());
+            assert_eq!(encoded.to_vec(), &values[..end]);
+            start = end;
+            if end == values.len() {
+                break;
+            }
+        }
+        assert_eq!(encoded, EncodedU64::from_slice(&values));
+    }
+
+    #[test]
+    fn iter() {
+        let values = values();
+        for &count in &[0, 5, 8, 13, 3000] {
+            let encoded = EncodedU64::from_slice(&values[..count]);
+
+            let mut iter = encoded.iter();
+            assert_eq!(iter.len(), count);
+            if count > 0 {
+                iter.next();
+                assert_eq!(iter.len(), count - 1);
+            }
+
+            assert_eq!(encoded.iter().collect::<Vec<_>>(), &values[..count]);
+            assert_eq!(encoded.into_iter().collect::<Vec<_>>(), &values[..count]);
+        }
+    }
+
+    #[test]
+    fn get() {
+        let values = values();
+        let encoded = EncodedU64::from_slice(&values);
+        for (i, &value) in values.iter().enumerate() {
+            assert_eq!(encoded.get(i), Some(value));
+        }
+        assert_eq!(encoded.get(values.len()), None);
+
+        assert_eq!(encoded.get_range(5..21).unwrap(), &values[5..21]);
+        assert_eq!(encoded.get_range(3000..3000).unwrap(), &[]);
+        assert_eq!(encoded.get_range(2990..3001), None);
+    }
+
+    #[test]
+    fn bad_count() {
+        let buf = EncodedU64::from_slice(&values()).to_bytes();
+        assert_eq!(
+            EncodedU64Ref::new(&buf, usize::MAX).unwrap_err(),
+            DecodeError::KeysTruncated {
+                needed: usize::MAX,
+                available: buf.len(),
+            }
+        );
+    }
+}
@@ -0,0 +1,453 @@
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use crate::alphabet::{self, Standard};
+use crate::error::ParseBackendError;
+use crate::transform::Transform;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::{avx2, ssse3};
+use crate::{
+    check_keys_len, decode_scalar, decode_scalar_with, decode_swar, decode_swar_with,
+    encode_scalar, encode_scalar_with, encoded_data_len, keys_len, max_compressed_len, DecodeError,
+    EncodeError,
+};
+
+// the environment variable which overrides the backend picked by `Engine::new`
+const BACKEND_VAR: &str = "STREAM_VBYTE64_BACKEND";
+
+type EncodeFn = unsafe fn(&[u64], &mut [u8], &mut [u8]) -> usize;
+type DecodeFn = unsafe fn(&mut [u64], &[u8], &[u8]) -> usize;
+
+/// A set of encoding and decoding kernels.
+///
+/// Backends are ordered by the CPU features they need, so each one needs everything the backends
+/// before it do.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Backend {
+    /// Encodes and decodes a value at a time.
+    Scalar,
+    /// Encodes a value at a time and decodes 8 bytes at a time.
+    Swar,
+    /// Uses 128 bit SSSE3 shuffles.
+    Ssse3,
+    /// Uses 256 bit AVX2 shuffles.
+    Avx2,
+}
+
+impl Backend {
+    /// Returns the fastest backend the CPU supports.
+    pub fn detect() -> Backend {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if is_x86_feature_detected!("avx2") {
+                return Backend::Avx2;
+            } else if is_x86_feature_detected!("ssse3") {
+                return Backend::Ssse3;
+            }
+        }
+
+        Backend::Swar
+    }
+
+    /// Returns `true` if the CPU supports the backend.
+    pub fn is_supported(self) -> bool {
+        self <= Backend::detect()
+    }
+
+    /// Returns the name of the backend, as accepted by its `FromStr` implementation.
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Scalar => "scalar",
+            Backend::Swar => "swar",
+            Backend::Ssse3 => "ssse3",
+            Backend::Avx2 => "avx2",
+        }
+    }
+
+    // returns the backend used by the crate's free functions
+    pub(crate) fn current() -> Backend {
+        Engine::new().backend
+    }
+
+    pub(crate) unsafe fn encode_with<T>(
+        self,
+        input: &[u64],
+        keys: &mut [u8],
+        data: &mut [u8],
+        transform: &mut T,
+    ) -> usize
+    where
+        T: Transform,
+    {
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Avx2 => avx2::encode_avx_with(input, keys, data, transform),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Ssse3 => ssse3::encode_ssse3_with(input, keys, data, transform),
+            _ => encode_scalar_with(input, keys, data, transform),
+        }
+    }
+
+    pub(crate) unsafe fn decode_with<T>(
+        self,
+        output: &mut [u64],
+        keys: &[u8],
+        data: &[u8],
+        transform: &mut T,
+    ) -> usize
+    where
+        T: Transform,
+    {
+        match self {
+            Backend::Scalar => decode_scalar_with(output, keys, data, transform),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Avx2 => avx2::decode_avx_with(output, keys, data, transform),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Ssse3 => ssse3::decode_ssse3_with(output, keys, data, transform),
+            _ => decode_swar_with(output, keys, data, transform),
+        }
+    }
+
+    fn kernels(self) -> (EncodeFn, DecodeFn) {
+        match self {
+            Backend::Scalar => (encode_scalar, decode_scalar),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Avx2 => (avx2::encode_avx, avx2::decode_avx),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::Ssse3 => (ssse3::encode_ssse3, ssse3::decode_ssse3),
+            _ => (encode_scalar, decode_swar),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(self.name())
+    }
+}
+
+impl FromStr for Backend {
+    type Err = ParseBackendError;
+
+    fn from_str(s: &str) -> Result<Backend, ParseBackendError> {
+        [
+            Backend::Scalar,
+            Backend::Swar,
+            Backend::Ssse3,
+            Backend::Avx2,
+        ]
+        .into_iter()
+        .find(|backend| backend.name().eq_ignore_ascii_case(s))
+        .ok_or_else(|| ParseBackendError(s.to_string()))
+    }
+}
+
+/// Encodes and decodes with a fixed backend.
+///
+/// The crate's free functions use `Engine::new()`, and the engine has a method for each of those
+/// which dispatch to a kernel. `Decoder::with_engine` takes an engine too, while `EncodedU64`,
+/// `EncodedU64Ref`, `StreamEncoder` and `StreamDecoder` always use `Engine::new()`.
+#[derive(Debug, Copy, Clone)]
+pub struct Engine {
+    backend: Backend,
+    encode: EncodeFn,
+    decode: DecodeFn,
+}
+
+impl Default for Engine {
+    fn default() -> Engine {
+        Engine::new()
+    }
+}
+
+impl Engine {
+    /// Returns an engine using the backend named by the `STREAM_VBYTE64_BACKEND` environment
+    /// variable, or the fastest one the CPU supports if it's unset, doesn't name a backend, or
+    /// names one the CPU doesn't support.
+    ///
+    /// The backend is picked the first time this is called, and the same one is used from then on.
+    pub fn new() -> Engine {
+        static ENGINE: OnceLock<Engine> = OnceLock::new();
+
+        *ENGINE.get_or_init(|| {
+            let backend = env::var(BACKEND_VAR)
+                .ok()
+                .and_then(|name| name.parse::<Backend>().ok())
+                .filter(|backend| backend.is_supported())
+                .unwrap_or_else(Backend::detect);
+            Engine::with_backend(backend)
+        })
+    }
+
+    /// Returns an engine using `backend`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the CPU doesn't support `backend`.
+    pub fn with_backend(backend: Backend) -> Engine {
+        assert!(
+            backend.is_supported(),
+            "the {} backend is not supported by this CPU",
+            backend
+        );
+
+        let (encode, decode) = backend.kernels();
+        Engine {
+            backend,
+            encode,
+            decode,
+        }
+    }
+
+    /// Returns the engine's backend.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Like the crate's `encode`, using this engine's backend.
+    pub fn encode(&self, input: &[u64], buf: &mut [u8]) -> usize {
+        assert!(buf.len() >= max_compressed_len(input.len()));
+        let keys_len = keys_len(input.len());
+        let (keys, data) = buf.split_at_mut(keys_len);
+
+        let written = unsafe { (self.encode)(input, keys, data) };
+
+        keys_len + written
+    }
+
+    /// Like the crate's `try_encode`, using this engine's backend.
+    pub fn try_encode(&self, input: &[u64], buf: &mut [u8]) -> Result<usize, EncodeError> {
+        let keys_len = keys_len(input.len());
+        let data_len = encoded_data_len(self.backend, input);
+        let needed = keys_len + data_len;
+        if buf.len() < needed {
+            return Err(EncodeError::BufferTooSmall {
+                needed,
+                available: buf.len(),
+            });
+        }
+
+        let (keys, data) = buf[..needed].split_at_mut(keys_len);
+        let written = unsafe { (self.encode)(input, keys, data) };
+        debug_assert_eq!(written, data_len);
+
+        Ok(needed)
+    }
+
+    /// Like the crate's `encode_append`, using this engine's backend.
+    pub fn encode_append(&self, input: &[u64], buf: &mut Vec<u8>) -> usize {
+        // size the output exactly, so the only bytes zeroed are ones the encoder overwrites rather
+        // than the up to 9 bytes per value of `max_compressed_len`
+        let keys_len = keys_len(input.len());
+        let data_len = encoded_data_len(self.backend, input);
+        let start = buf.len();
+        buf.resize(start + keys_len + data_len, 0);
+
+        let (keys, data) = buf[start..].split_at_mut(keys_len);
+        let written = unsafe { (self.encode)(input, keys, data) };
+        debug_assert_eq!(written, data_len);
+
+        keys_len + written
+    }
+
+    /// Like the crate's `encoded_len`, using this engine's backend.
+    pub fn encoded_len(&self, input: &[u64]) -> usize {
+        keys_len(input.len()) + encoded_data_len(self.backend, input)
+    }
+
+    /// Like the crate's `decode`, using this engine's backend.
+    pub fn decode(&self, output: &mut [u64], buf: &[u8]) -> usize {
+        let keys_len = keys_len(output.len());
+        let (keys, data) = buf.split_at(keys_len);
+        let data_len = match self.try_compressed_data_len(output.len(), keys) {
+            Ok(len) => len,
+            Err(e) => panic!("{}", e),
+        };
+        assert!(data.len() >= data_len, "{} < {}", data.len(), data_len);
+
+        unsafe { (self.decode)(output, keys, data) }
+    }
+
+    /// Like the crate's `try_decode`, using this engine's backend.
+    pub fn try_decode(&self, output: &mut [u64], buf: &[u8]) -> Result<usize, DecodeError> {
+        let keys_len = check_keys_len(output.len(), buf.len())?;
+        let (keys, data) = buf.split_at(keys_len);
+        let data_len = self.try_compressed_data_len(output.len(), keys)?;
+        if data.len() < data_len {
+            return Err(DecodeError::DataTruncated {
+                needed: data_len,
+                available: data.len(),
+            });
+        }
+
+        let read = unsafe { (self.decode)(output, keys, &data[..data_len]) };
+        debug_assert_eq!(read, data_len);
+        Ok(keys_len + read)
+    }
+
+    /// Like the crate's `try_compressed_data_len`, using this engine's backend.
+    pub fn try_compressed_data_len(
+        &self,
+        values: usize,
+        keys: &[u8],
+    ) -> Result<usize, DecodeError> {
+        alphabet::data_len::<Standard>(self.backend, values, keys)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{max_compressed_len_for, stream32, Alphabet, Decoder};
+
+    #[test]
+    fn parse() {
+        for backend in [
+            Backend::Scalar,
+            Backend::Swar,
+            Backend::Ssse3,
+            Backend::Avx2,
+        ] {
+            assert_eq!(backend.name().parse(), Ok(backend));
+        }
+        assert_eq!("AVX2".parse(), Ok(Backend::Avx2));
+        assert!("neon".parse::<Backend>().is_err());
+    }
+
+    #[test]
+    fn match_backends() {
+        let values = (0..1003)
+            .map(|v: u64| v.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (v * 3 % 8 * 8))
+            .collect::<Vec<_>>();
+        let mut expected = vec![0; max_compressed_len(values.len())];
+        let len = Engine::with_backend(Backend::Scalar).encode(&values, &mut expected);
+
+        for backend in [
+            Backend::Scalar,
+            Backend::Swar,
+            Backend::Ssse3,
+            Backend::Avx2,
+        ] {
+            if !backend.is_supported() {
+                continue;
+            }
+            let engine = Engine::with_backend(backend);
+
+            let mut buf = vec![0; max_compressed_len(values.len())];
+            assert_eq!(engine.encode(&values, &mut buf), len);
+            assert_eq!(buf[..len], expected[..len]);
+            assert_eq!(engine.try_encode(&values, &mut buf), Ok(len));
+
+            let mut out = vec![0; values.len()];
+            assert_eq!(engine.try_decode(&mut out, &expected[..len]), Ok(len));
+            assert_eq!(out, values);
+        }
+    }
+
+    // runs everything an engine can do, checking that it round trips
+    fn exercise(engine: &Engine) {
+        let values = (0..1003)
+            .map(|v: u64| v.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (v * 3 % 8 * 8))
+            .collect::<Vec<_>>();
+        let signed = values.iter().map(|&v| v as i64 >> 8).collect::<Vec<_>>();
+        let small = values.iter().map(|&v| v as u32).collect::<Vec<_>>();
+        let mut buf = vec![0; max_compressed_len_for(values.len())];
+        let mut out = vec![0; values.len()];
+        let mut signed_out = vec![0; values.len()];
+        let mut small_out = vec![0; values.len()];
+
+        let len = engine.encode(&values, &mut buf);
+        assert_eq!(engine.encoded_len(&values), len);
+        assert_eq!(engine.try_encode(&values, &mut buf), Ok(len));
+        assert_eq!(
+            engine.try_compressed_data_len(values.len(), &buf),
+            Ok(len - keys_len(values.len()))
+        );
+        engine.decode(&mut out, &buf);
+        assert_eq!(out, values);
+        let (len, index) = engine.encode_indexed(&values, &mut buf, 4);
+        engine.decode_range(&buf, &index, 13..998, &mut out[13..998]);
+        assert_eq!(out, values);
+        let mut decoder = Decoder::with_engine(values.len(), engine);
+        assert!(decoder.feed(&buf[..len], &mut out).finished);
+        assert_eq!(out, values);
+
+        let mut appended = vec![1, 2, 3];
+        assert_eq!(engine.encode_append(&values, &mut appended), len);
+        assert_eq!(appended[3..], buf[..len]);
+        assert_eq!(engine.try_decode(&mut out, &buf[..len]), Ok(len));
+        assert_eq!(out, values);
+
+        engine.encode_delta(&values, 5, &mut buf);
+        engine.decode_delta(&mut out, 5, &buf);
+        assert_eq!(out, values);
+
+        engine.encode_delta_of_delta(&values, 5, &mut buf);
+        engine.decode_delta_of_delta(&mut out, 5, &buf);
+        assert_eq!(out, values);
+
+        engine.encode_for(&values, &mut buf);
+        engine.decode_for(&mut out, &buf);
+        assert_eq!(out, values);
+        assert!(engine.try_decode_for(&mut out, &buf).is_ok());
+        assert_eq!(out, values);
+
+        engine.encode_i64(&signed, &mut buf);
+        engine.decode_i64(&mut signed_out, &buf);
+        assert_eq!(signed_out, signed);
+
+        engine.encode_delta_i64(&signed, -5, &mut buf);
+        engine.decode_delta_i64(&mut signed_out, -5, &buf);
+        assert_eq!(signed_out, signed);
+
+        for alphabet in [Alphabet::Standard, Alphabet::Sparse] {
+            let len = engine.encode_with_alphabet(&values, &mut buf, alphabet);
+            assert_eq!(
+                engine.decode_with_alphabet(&mut out, &buf, alphabet),
+                Ok(len)
+            );
+            assert_eq!(out, values);
+        }
+
+        let len = engine.encode_u32(&small, &mut buf);
+        assert_eq!(engine.try_decode_u32(&mut small_out, &buf), Ok(len));
+        assert!(len <= stream32::max_compressed_len(small.len()));
+        engine.decode_u32(&mut small_out, &buf);
+        assert_eq!(small_out, small);
+    }
+
+    #[test]
+    fn all_backends() {
+        for backend in [
+            Backend::Scalar,
+            Backend::Swar,
+            Backend::Ssse3,
+            Backend::Avx2,
+        ] {
+            if backend.is_supported() {
+                exercise(&Engine::with_backend(backend));
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn pinned_backend() {
+        let calls = || avx2::CALLS.with(|calls| calls.get());
+
+        let start = calls();
+        for backend in [Backend::Scalar, Backend::Swar, Backend::Ssse3] {
+            if backend.is_supported() {
+                exercise(&Engine::with_backend(backend));
+            }
+        }
+        assert_eq!(calls(), start);
+
+        if Backend::Avx2.is_supported() {
+            exercise(&Engine::with_backend(Backend::Avx2));
+            assert!(calls() > start);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::error::Error;
+use std::fmt;
+
+/// An error returned when values cannot be encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The output buffer is too small to hold the encoded values.
+    BufferTooSmall { needed: usize, available: usize },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::BufferTooSmall { needed, available } => write!(
+                fmt,
+                "buffer too small: needed {} bytes but only {} are available",
+                needed, available
+            ),
+        }
+    }
+}
+
+impl Error for EncodeError {}
+
+/// An error returned when a buffer cannot be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer is too short to hold the keys for the requested number of values.
+    KeysTruncated { needed: usize, available: usize },
+    /// The data section is shorter than the keys say it should be.
+    DataTruncated { needed: usize, available: usize },
+    /// A framed buffer does not start with the expected magic number.
+    InvalidMagic,
+    /// A framed buffer was written with an unknown format version.
+    UnsupportedVersion(u8),
+    /// A framed buffer has flags set which are not understood.
+    UnsupportedFlags(u8),
+    /// A framed buffer has a truncated or inconsistent header.
+    InvalidHeader,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::KeysTruncated { needed, available } => write!(
+                fmt,
+                "keys truncated: needed {} bytes but only {} are available",
+                needed, available
+            ),
+            DecodeError::DataTruncated { needed, available } => write!(
+                fmt,
+                "data truncated: needed {} bytes but only {} are available",
+                needed, available
+            ),
+            DecodeError::InvalidMagic => fmt.write_str("invalid magic number"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(fmt, "unsupported format version {}", version)
+            }
+            DecodeError::UnsupportedFlags(flags) => write!(fmt, "unsupported flags {:#04x}", flags),
+            DecodeError::InvalidHeader => fmt.write_str("invalid header"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+/// An error returned when a string does not name a `Backend`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseBackendError(pub(crate) String);
+
+impl fmt::Display for ParseBackendError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "unknown backend `{}`", self.0)
+    }
+}
+
+impl Error for ParseBackendError {}
@@ -0,0 +1,155 @@
+use crate::{
+    checked_keys_len, decode_to_vec, encode_append, keys_len, try_compressed_data_len, DecodeError,
+};
+
+// A framed buffer looks like:
+//
+// magic: [u8; 4]
+// version: u8
+// flags: u8
+// count: varint
+// data length: varint
+// keys: [u8; keys_len(count)]
+// data: [u8; data length]
+const MAGIC: [u8; 4] = *b"SVB8";
+const VERSION: u8 = 1;
+
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub(crate) fn read_varint(buf: &mut &[u8]) -> Result<u64, DecodeError> {
+    let mut value = 0;
+    for (i, &byte) in buf.iter().enumerate().take(10) {
+        let bits = (byte & 0x7f) as u64;
+        if i == 9 && bits > 1 {
+            break;
+        }
+        value |= bits << (7 * i);
+        if byte & 0x80 == 0 {
+            *buf = &buf[i + 1..];
+            return Ok(value);
+        }
+    }
+
+    Err(DecodeError::InvalidHeader)
+}
+
+/// Encodes `input` into a self-describing buffer which records the number of values.
+pub fn encode_framed(input: &[u64]) -> Vec<u8> {
+    let mut buf = vec![];
+    buf.extend_from_slice(&MAGIC);
+    buf.push(VERSION);
+    buf.push(0);
+    write_varint(&mut buf, input.len() as u64);
+
+    let mut body = vec![];
+    encode_append(input, &mut body);
+    write_varint(&mut buf, (body.len() - keys_len(input.len())) as u64);
+    buf.extend_from_slice(&body);
+
+    buf
+}
+
+/// Decodes a buffer produced by `encode_framed`.
+pub fn decode_framed(buf: &[u8]) -> Result<Vec<u64>, DecodeError> {
+    if buf.len() < 6 {
+        return Err(DecodeError::InvalidHeader);
+    }
+    if buf[..4] != MAGIC {
+        return Err(DecodeError::InvalidMagic);
+    }
+    if buf[4] != VERSION {
+        return Err(DecodeError::UnsupportedVersion(buf[4]));
+    }
+    if buf[5] != 0 {
+        return Err(DecodeError::UnsupportedFlags(buf[5]));
+    }
+
+    let mut body = &buf[6..];
+    let count = read_varint(&mut body)?;
+    let data_len = read_varint(&mut body)?;
+    if count > usize::MAX as u64 || data_len > usize::MAX as u64 {
+        return Err(DecodeError::InvalidHeader);
+    }
+    let count = count as usize;
+    let data_len = data_len as usize;
+
+    let keys_len = checked_keys_len(count).ok_or(DecodeError::InvalidHeader)?;
+    let keys = &body[..keys_len.min(body.len())];
+    let actual = try_compressed_data_len(count, keys)?;
+    if actual != data_len {
+        return Err(DecodeError::InvalidHeader);
+    }
+    // anything after the data isn't part of the frame
+    if body.len() - keys_len > data_len {
+        return Err(DecodeError::InvalidHeader);
+    }
+
+    decode_to_vec(body, count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for &count in &[0, 1, 7, 8, 9, 100, 4090] {
+            let values = (0..count)
+                .map(|v| v * (u64::MAX / 4090))
+                .collect::<Vec<_>>();
+            let buf = encode_framed(&values);
+            assert_eq!(decode_framed(&buf).unwrap(), values);
+
+            for len in 0..buf.len() {
+                assert!(decode_framed(&buf[..len]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn bad_header() {
+        let mut buf = encode_framed(&[1, 2, 3]);
+        buf[5] = 1;
+        assert_eq!(decode_framed(&buf), Err(DecodeError::UnsupportedFlags(1)));
+        buf[4] = 2;
+        assert_eq!(decode_framed(&buf), Err(DecodeError::UnsupportedVersion(2)));
+        buf[0] = b'X';
+        assert_eq!(decode_framed(&buf), Err(DecodeError::InvalidMagic));
+    }
+
+    #[test]
+    fn bad_count() {
+        let mut buf = encode_framed(&[]);
+        buf.truncate(6);
+        write_varint(&mut buf, u64::MAX);
+        write_varint(&mut buf, 0);
+        assert_eq!(decode_framed(&buf), Err(DecodeError::InvalidHeader));
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let mut buf = encode_framed(&[1, 2, 3]);
+        buf.push(0);
+        assert_eq!(decode_framed(&buf), Err(DecodeError::InvalidHeader));
+    }
+
+    #[test]
+    fn varint() {
+        for &value in &[0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = vec![];
+            write_varint(&mut buf, value);
+            let mut slice = &buf[..];
+            assert_eq!(read_varint(&mut slice), Ok(value));
+            assert!(slice.is_empty());
+        }
+
+        let mut slice = &[0xff; 10][..];
+        assert_eq!(read_varint(&mut slice), Err(DecodeError::InvalidHeader));
+    }
+}
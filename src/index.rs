@@ -0,0 +1,280 @@
+use std::ops::Range;
+
+use crate::alphabet::{self, Standard};
+use crate::engine::{Backend, Engine};
+use crate::transform::Plain;
+use crate::{check_keys_len, keys_len, tables, DecodeError};
+
+/// A sparse index of data offsets into an encoded buffer, allowing random access to its values.
+///
+/// The index records the data offset of every `interval`th key group. Finding a value requires
+/// summing the lengths of at most `interval - 1` key groups past the nearest entry, so smaller
+/// intervals trade memory for faster lookups.
+#[derive(Debug, Clone)]
+pub struct Index {
+    count: usize,
+    interval: usize,
+    offsets: Vec<usize>,
+}
+
+impl Index {
+    /// Builds an index from the keys of a buffer holding `count` encoded values.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is 0.
+    pub fn new(buf: &[u8], count: usize, interval: usize) -> Result<Index, DecodeError> {
+        Index::new_with(Backend::current(), buf, count, interval)
+    }
+
+    fn new_with(
+        backend: Backend,
+        buf: &[u8],
+        count: usize,
+        interval: usize,
+    ) -> Result<Index, DecodeError> {
+        assert!(interval > 0, "interval must be nonzero");
+
+        let keys_len = check_keys_len(count, buf.len())?;
+        let data_len = alphabet::data_len::<Standard>(backend, count, buf)?;
+        if buf.len() - keys_len < data_len {
+            return Err(DecodeError::DataTruncated {
+                needed: data_len,
+                available: buf.len() - keys_len,
+            });
+        }
+
+        let mut offsets = Vec::with_capacity(count.div_ceil(8).div_ceil(interval));
+        let mut offset = 0;
+        for (group, key) in buf[..keys_len].chunks(3).enumerate() {
+            if group % interval == 0 {
+                offsets.push(offset);
+            }
+            offset += group_len(key);
+        }
+
+        Ok(Index {
+            count,
+            interval,
+            offsets,
+        })
+    }
+
+    /// Returns the number of values in the indexed buffer.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Returns `true` if the indexed buffer holds no values.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the number of key groups between entries of the index.
+    pub fn interval(&self) -> usize {
+        self.interval
+    }
+
+    // returns the offset into the data section of the `i`th value
+    fn seek(&self, keys: &[u8], i: usize) -> usize {
+        let entry = i / 8 / self.interval;
+        seek_from(keys, entry * self.interval, self.offsets[entry], i)
+    }
+}
+
+// returns the offset into the data section of the `i`th value, starting from the data offset of
+// an earlier key group
+pub(crate) fn seek_from(keys: &[u8], group: usize, mut offset: usize, i: usize) -> usize {
+    for g in group..i / 8 {
+        offset += group_len(&keys[g * 3..g * 3 + 3]);
+    }
+
+    if !i.is_multiple_of(8) {
+        let key = read_key(&keys[i / 8 * 3..i / 8 * 3 + 3]);
+        for j in 0..i % 8 {
+            offset += code_len(key, j);
+        }
+    }
+
+    offset
+}
+
+pub(crate) fn read_key(key: &[u8]) -> u32 {
+    key[0] as u32 | (key[1] as u32) << 8 | (key[2] as u32) << 16
+}
+
+pub(crate) fn group_len(key: &[u8]) -> usize {
+    let key = read_key(key);
+    tables::LENGTH[key as usize & ((1 << 12) - 1)] as usize
+        + tables::LENGTH[key as usize >> 12] as usize
+}
+
+pub(crate) fn code_len(key: u32, i: usize) -> usize {
+    (key >> (3 * i) & 0b111) as usize + 1
+}
+
+pub(crate) fn read_value(data: &[u8], len: usize) -> u64 {
+    let mut value = [0; 8];
+    value[..len].copy_from_slice(&data[..len]);
+    u64::from_le_bytes(value)
+}
+
+/// Like `encode`, but also builds an `Index` of the encoded buffer.
+pub fn encode_indexed(input: &[u64], buf: &mut [u8], interval: usize) -> (usize, Index) {
+    Engine::new().encode_indexed(input, buf, interval)
+}
+
+/// Returns the `i`th value of the buffer described by `index`.
+///
+/// # Panics
+///
+/// Panics if `i` is out of bounds, or if `buf` is not the buffer that `index` was built from.
+pub fn get(buf: &[u8], index: &Index, i: usize) -> u64 {
+    assert!(
+        i < index.count,
+        "index out of bounds: the len is {} but the index is {}",
+        index.count,
+        i
+    );
+
+    let (keys, data) = buf.split_at(keys_len(index.count));
+    let offset = index.seek(keys, i);
+    let key = read_key(&keys[i / 8 * 3..i / 8 * 3 + 3]);
+    read_value(&data[offset..], code_len(key, i % 8))
+}
+
+/// Decodes the values in `range` of the buffer described by `index` into `output`.
+///
+/// # Panics
+///
+/// Panics if `range` is out of bounds, if `output` is not the same length as `range`, or if `buf`
+/// is not the buffer that `index` was built from.
+pub fn decode_range(buf: &[u8], index: &Index, range: Range<usize>, output: &mut [u64]) {
+    Engine::new().decode_range(buf, index, range, output)
+}
+
+impl Engine {
+    /// Like the crate's `encode_indexed`, using this engine's backend.
+    pub fn encode_indexed(&self, input: &[u64], buf: &mut [u8], interval: usize) -> (usize, Index) {
+        let written = self.encode(input, buf);
+        let index =
+            Index::new_with(self.backend(), &buf[..written], input.len(), interval).unwrap();
+        (written, index)
+    }
+
+    /// Like the crate's `decode_range`, using this engine's backend.
+    pub fn decode_range(&self, buf: &[u8], index: &Index, range: Range<usize>, output: &mut [u64]) {
+        assert!(
+            range.start <= range.end && range.end <= index.count,
+            "range {:?} out of bounds for length {}",
+            range,
+            index.count
+        );
+        assert_eq!(output.len(), range.len());
+
+        if range.is_empty() {
+            return;
+        }
+
+        let (keys, data) = buf.split_at(keys_len(index.count));
+        let offset = index.seek(keys, range.start);
+        decode_from(self.backend(), keys, data, range.start, offset, output);
+    }
+}
+
+// decodes values starting from the `start`th, which is at `offset` in the data section
+pub(crate) fn decode_from(
+    backend: Backend,
+    keys: &[u8],
+    data: &[u8],
+    start: usize,
+    mut offset: usize,
+    output: &mut [u64],
+) {
+    let end = start + output.len();
+
+    // handle the tail of a partial first key group one value at a time
+    let mut i = start;
+    if !i.is_multiple_of(8) {
+        let key = read_key(&keys[i / 8 * 3..i / 8 * 3 + 3]);
+        while !i.is_multiple_of(8) && i < end {
+            let len = code_len(key, i % 8);
+            output[i - start] = read_value(&data[offset..], len);
+            offset += len;
+            i += 1;
+        }
+    }
+
+    // the rest starts on a key group boundary, so the normal decoders can take over
+    let output = &mut output[i - start..];
+    let keys = &keys[i / 8 * 3..];
+    let data = &data[offset..];
+    let data_len = alphabet::data_len::<Standard>(backend, output.len(), keys).unwrap();
+    assert!(data.len() >= data_len, "{} < {}", data.len(), data_len);
+
+    unsafe {
+        backend.decode_with(output, keys, &data[..data_len], &mut Plain);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{encode, max_compressed_len};
+
+    fn values() -> Vec<u64> {
+        (0..1000)
+            .map(|v: u64| v.wrapping_mul(0x0123_4567_89ab_cdef) >> (v * 5 % 8 * 8))
+            .collect()
+    }
+
+    #[test]
+    fn get_all() {
+        let values = values();
+        let mut buf = vec![0; max_compressed_len(values.len())];
+        for &interval in &[1, 3, 16, 1000] {
+            let (written, index) = encode_indexed(&values, &mut buf, interval);
+            for (i, &value) in values.iter().enumerate() {
+                assert_eq!(get(&buf[..written], &index, i), value);
+            }
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        let values = values();
+        let mut buf = vec![0; max_compressed_len(values.len())];
+        let written = encode(&values, &mut buf);
+        let index = Index::new(&buf[..written], values.len(), 4).unwrap();
+
+        for &(start, end) in &[
+            (0, 0),
+            (0, 1000),
+            (3, 5),
+            (7, 9),
+            (8, 16),
+            (13, 500),
+            (995, 1000),
+        ] {
+            let mut out = vec![0; end - start];
+            decode_range(&buf[..written], &index, start..end, &mut out);
+            assert_eq!(out, &values[start..end]);
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let values = values();
+        let mut buf = vec![0; max_compressed_len(values.len())];
+        let written = encode(&values, &mut buf);
+        assert!(Index::new(&buf[..written - 1], values.len(), 4).is_err());
+        assert!(Index::new(&buf[..10], values.len(), 4).is_err());
+        assert_eq!(
+            Index::new(&buf, usize::MAX, 4).unwrap_err(),
+            DecodeError::KeysTruncated {
+                needed: usize::MAX,
+                available: buf.len(),
+            }
+        );
+    }
+}
@@ -1,81 +1,176 @@
-#![feature(target_feature, cfg_target_feature, stdsimd)]
-
+use std::cmp;
 use std::ptr;
-use std::slice;
-
-#[cfg(target_arch = "x86")]
-use std::arch::x86::*;
-#[cfg(target_arch = "x86_64")]
-use std::arch::x86_64::*;
 
+pub use alphabet::{
+    compressed_data_len_with_alphabet, decode_with_alphabet, encode_with_alphabet, Alphabet,
+};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use avx2::{decode_avx, encode_avx, encode_avx_table_free};
+pub use delta::{decode_delta, encode_delta};
+pub use encoded::{EncodedU64, EncodedU64IntoIter, EncodedU64Iter, EncodedU64Ref};
+pub use engine::{Backend, Engine};
+pub use error::{DecodeError, EncodeError, ParseBackendError};
+pub use frame::{decode_framed, encode_framed};
+pub use index::{decode_range, encode_indexed, get, Index};
+pub use push::{Decoder, Progress};
+pub use reference::{decode_for, encode_for, max_compressed_len_for, try_decode_for};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use ssse3::{decode_ssse3, encode_ssse3};
+pub use stream::{StreamDecoder, StreamEncoder};
+pub use zigzag::{
+    decode_delta_i64, decode_delta_of_delta, decode_i64, encode_delta_i64, encode_delta_of_delta,
+    encode_i64,
+};
+
+use crate::alphabet::{Codes, Standard};
+use crate::transform::{Plain, Transform};
+
+mod alphabet;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod avx2;
+mod delta;
+mod encoded;
+mod engine;
+mod error;
+mod frame;
+mod index;
+mod push;
+mod reference;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod ssse3;
+mod stream;
+pub mod stream32;
 pub mod tables;
+mod transform;
+mod zigzag;
 
+#[allow(clippy::manual_div_ceil)]
 pub fn keys_len(values: usize) -> usize {
     ((values + 7) / 8) * 3
 }
 
+// like `keys_len`, but returns `None` rather than overflowing for absurd value counts
+pub(crate) fn checked_keys_len(values: usize) -> Option<usize> {
+    (values.checked_add(7)? / 8).checked_mul(3)
+}
+
+// returns `keys_len(values)`, or an error if it doesn't fit in `available` bytes
+pub(crate) fn check_keys_len(values: usize, available: usize) -> Result<usize, DecodeError> {
+    match checked_keys_len(values) {
+        Some(needed) if needed <= available => Ok(needed),
+        needed => Err(DecodeError::KeysTruncated {
+            needed: needed.unwrap_or(usize::MAX),
+            available,
+        }),
+    }
+}
+
 pub fn max_compressed_len(values: usize) -> usize {
     keys_len(values) + values * 8
 }
 
+/// Returns the length of the data section of a buffer holding `values` encoded values.
+///
+/// # Panics
+///
+/// Panics if `data` is shorter than `keys_len(values)`.
 pub fn compressed_data_len(values: usize, data: &[u8]) -> usize {
-    let mut len = 0;
-    let keys = keys_len(values) / 3;
-    for i in 0..keys {
-        let mut key = 0u32;
-        unsafe {
-            ptr::copy_nonoverlapping(
-                data.as_ptr().offset(3 * i as isize),
-                &mut key as *mut u32 as *mut u8,
-                3,
-            );
-        }
-        key = u32::from_le(key);
-        len += tables::LENGTH[key as usize & ((1 << 12) - 1)] as usize;
-        len += tables::LENGTH[key as usize >> 12] as usize;
+    match try_compressed_data_len(values, data) {
+        Ok(len) => len,
+        Err(e) => panic!("{}", e),
     }
+}
+
+/// Like `compressed_data_len`, but checks that `keys` holds all of the keys for `values` values.
+///
+/// The padding codes of a trailing partial key group are ignored, so the result is exact even if
+/// they are not zero.
+pub fn try_compressed_data_len(values: usize, keys: &[u8]) -> Result<usize, DecodeError> {
+    Engine::new().try_compressed_data_len(values, keys)
+}
 
-    len - (8 - values % 8)
+// returns the total data length of the key groups in `keys`, which must be a multiple of 3 long
+fn keys_data_len_scalar<A>(keys: &[u8]) -> usize
+where
+    A: Codes,
+{
+    keys.chunks_exact(3)
+        .map(|group| {
+            let key = group[0] as usize | (group[1] as usize) << 8 | (group[2] as usize) << 16;
+            A::LENGTH[key & ((1 << 12) - 1)] as usize + A::LENGTH[key >> 12] as usize
+        })
+        .sum()
 }
 
 unsafe fn encode_single(value: u64, out: &mut *mut u8) -> u8 {
     let value = value.to_le();
     if value < 1 << 8 {
         **out = value as u8;
-        *out = out.offset(1);
+        *out = out.add(1);
         0
     } else if value < 1 << 16 {
         ptr::copy_nonoverlapping(&value as *const u64 as *const u8, *out, 2);
-        *out = out.offset(2);
+        *out = out.add(2);
         1
     } else if value < 1 << 24 {
         ptr::copy_nonoverlapping(&value as *const u64 as *const u8, *out, 3);
-        *out = out.offset(3);
+        *out = out.add(3);
         2
     } else if value < 1 << 32 {
         ptr::copy_nonoverlapping(&value as *const u64 as *const u8, *out, 4);
-        *out = out.offset(4);
+        *out = out.add(4);
         3
     } else if value < 1 << 40 {
         ptr::copy_nonoverlapping(&value as *const u64 as *const u8, *out, 5);
-        *out = out.offset(5);
+        *out = out.add(5);
         4
     } else if value < 1 << 48 {
         ptr::copy_nonoverlapping(&value as *const u64 as *const u8, *out, 6);
-        *out = out.offset(6);
+        *out = out.add(6);
         5
     } else if value < 1 << 56 {
         ptr::copy_nonoverlapping(&value as *const u64 as *const u8, *out, 7);
-        *out = out.offset(7);
+        *out = out.add(7);
         6
     } else {
         ptr::copy_nonoverlapping(&value as *const u64 as *const u8, *out, 8);
-        *out = out.offset(8);
+        *out = out.add(8);
         7
     }
 }
 
+/// Encodes `input` into separate key and data buffers, returning the number of data bytes written.
+///
+/// # Safety
+///
+/// `keys` must be at least `keys_len(input.len())` bytes long, and `data` must be large enough to
+/// hold the encoded data.
 pub unsafe fn encode_scalar(input: &[u64], keys: &mut [u8], data: &mut [u8]) -> usize {
+    encode_scalar_with(input, keys, data, &mut Plain)
+}
+
+unsafe fn encode_scalar_with<T>(
+    input: &[u64],
+    keys: &mut [u8],
+    data: &mut [u8],
+    transform: &mut T,
+) -> usize
+where
+    T: Transform,
+{
+    encode_scalar_alphabet::<Standard, T>(input, keys, data, transform)
+}
+
+unsafe fn encode_scalar_alphabet<A, T>(
+    input: &[u64],
+    keys: &mut [u8],
+    data: &mut [u8],
+    transform: &mut T,
+) -> usize
+where
+    A: Codes,
+    T: Transform,
+{
     debug_assert!(keys.len() >= keys_len(input.len()));
 
     if input.is_empty() {
@@ -92,11 +187,11 @@ pub unsafe fn encode_scalar(input: &[u64], keys: &mut [u8], data: &mut [u8]) ->
         if shift == 24 {
             key = key.to_le();
             ptr::copy_nonoverlapping(&key as *const u32 as *const u8, keyptr, 3);
-            keyptr = keyptr.offset(3);
+            keyptr = keyptr.add(3);
             shift = 0;
             key = 0;
         }
-        let code = encode_single(value, &mut dataptr);
+        let code = A::encode_single(transform.encode(value), &mut dataptr);
         key |= (code as u32) << shift;
         shift += 3;
     }
@@ -109,170 +204,85 @@ pub unsafe fn encode_scalar(input: &[u64], keys: &mut [u8], data: &mut [u8]) ->
     written
 }
 
-#[target_feature(enable = "avx2")]
-unsafe fn encode_block_avx(ptr: &mut *mut u8, value: __m256i) -> u32 {
-    // turn each byte into a 0 or 1 based on it being nonzero
-    let ones = _mm256_set1_epi8(1);
-    let mins = _mm256_min_epu8(value, ones);
-
-    // collect those bits into the high byte of each 32 bit part
-    // the multiply acts like a multi-bit shift
-    let low_shifter = 1 | 1 << 9 | 1 << 18;
-    let high_shifter = 1 | 1 << 9 | 1 << 18 | 1 << 27;
-    let shifts = _mm256_setr_epi32(
-        low_shifter,
-        high_shifter,
-        low_shifter,
-        high_shifter,
-        low_shifter,
-        high_shifter,
-        low_shifter,
-        high_shifter,
-    );
-    let bytemaps = _mm256_mullo_epi32(mins, shifts);
-
-    // use that as the mask vector to select the lane code
-    // first the low half
-    #[cfg_attr(rustfmt, rustfmt_skip)]
-    let low_lane_codes = _mm256_setr_epi8(
-        0, 3, 2, 3, 1, 3, 2, 3, -1, -1, -1, -1, -1, -1, -1, -1,
-        0, 3, 2, 3, 1, 3, 2, 3, -1, -1, -1, -1, -1, -1, -1, -1,
-    );
-    let low_shuffled_codes = _mm256_shuffle_epi8(low_lane_codes, bytemaps);
-    let low_shifted_codes = _mm256_slli_epi64(low_shuffled_codes, 32);
-
-    // now the high half
-    #[cfg_attr(rustfmt, rustfmt_skip)]
-    let high_lane_codes = _mm256_setr_epi8(
-        0, 7, 6, 7, 5, 7, 6, 7, 4, 7, 6, 7, 5, 7, 6, 7,
-        0, 7, 6, 7, 5, 7, 6, 7, 4, 7, 6, 7, 5, 7, 6, 7,
-    );
-    let high_shuffled_codes = _mm256_shuffle_epi8(high_lane_codes, bytemaps);
-
-    // overlay and take the max of the low and high codes
-    let lane_codes = _mm256_max_epu8(low_shifted_codes, high_shuffled_codes);
-
-    // now gather three copies of the lane codes from each lane
-    #[cfg_attr(rustfmt, rustfmt_skip)]
-    let gather_high = _mm256_setr_epi8(
-        -1, 15, 7, -1, -1, -1, -1, -1, -1, -1, 15, 7, -1, -1, -1, -1,
-        7, -1, -1, -1, -1, 15, 7, -1, 15, 7, -1, -1, -1, -1, -1, -1,
-    );
-    let shuffled_codes = _mm256_shuffle_epi8(lane_codes, gather_high);
-    let permuted = _mm256_permute4x64_epi64(shuffled_codes, 0b00001110);
-    let high_bytes = _mm256_or_si256(shuffled_codes, permuted);
-
-    // we're going to concatenate and sum the lane codes at the same time
-    let concat_low = 1 << 8 | 1 << 19 | 1 << 30;
-    let concat_high = 1 << 6 | 1 << 17;
-    let sum = 1 | 1 << 8 | 1 << 16 | 1 << 24;
-    let aggregators = _mm256_setr_epi32(concat_low, concat_high, sum, 0, 0, 0, 0, 0);
-
-    let code_and_length = _mm256_mullo_epi32(high_bytes, aggregators);
-
-    let code_low = _mm256_extract_epi8(code_and_length, 3) as u8;
-    let code_high = _mm256_extract_epi8(code_and_length, 7) as u8 & 0xf;
-    let code = (code_low as u32) | ((code_high as u32) << 8);
-    let length = _mm256_extract_epi8(code_and_length, 11) + 4;
-
-    let shuffle1 = tables::ENCODE_SHUFFLE_1[code as usize].v;
-    let data1 = _mm256_shuffle_epi8(value, shuffle1);
-
-    let shuffle2 = tables::ENCODE_SHUFFLE_2[code as usize].v;
-    let shuffled2 = _mm256_shuffle_epi8(value, shuffle2);
-    let data2 = _mm256_permute4x64_epi64(shuffled2, 0b00001110);
-
-    let data = _mm256_or_si256(data1, data2);
-    _mm256_storeu_si256(*ptr as *mut __m256i, data);
-    *ptr = ptr.offset(length as isize);
-
-    code
-}
-
-#[target_feature(enable = "avx2")]
-pub unsafe fn encode_avx(input: &[u64], keys: &mut [u8], data: &mut [u8]) -> usize {
-    debug_assert!(keys.len() >= keys_len(input.len()));
-
-    let mut inputptr = input.as_ptr();
-    let mut keyptr = keys.as_mut_ptr();
-    let mut dataptr = data.as_mut_ptr();
-
-    let count = input.len() / 8;
-    for _ in 0..count {
-        let data = _mm256_loadu_si256(inputptr as *const __m256i);
-        inputptr = inputptr.offset(4);
-        let code_low = encode_block_avx(&mut dataptr, data);
-
-        let data = _mm256_loadu_si256(inputptr as *const __m256i);
-        inputptr = inputptr.offset(4);
-        let code_high = encode_block_avx(&mut dataptr, data.into());
-
-        let code = (code_low as u32) | ((code_high as u32) << 12);
-        ptr::copy_nonoverlapping(&code as *const u32 as *const u8, keyptr, 3);
-        keyptr = keyptr.offset(3);
-    }
-
-    let written = dataptr as usize - data.as_ptr() as usize;
-    let input = slice::from_raw_parts(inputptr, input.len() - count * 8);
-    let keys = slice::from_raw_parts_mut(
-        keyptr,
-        keys.as_ptr().offset(keys.len() as isize) as usize - keyptr as usize,
-    );
-    let data = slice::from_raw_parts_mut(
-        dataptr,
-        data.as_ptr().offset(data.len() as isize) as usize - dataptr as usize,
-    );
-
-    encode_scalar(input, keys, data) + written
-}
-
 unsafe fn decode_single(ptr: &mut *const u8, code: u8) -> u64 {
     let mut value = 0;
     match code {
         0 => {
             value = **ptr as u64;
-            *ptr = ptr.offset(1);
+            *ptr = ptr.add(1);
         }
         1 => {
             ptr::copy_nonoverlapping(*ptr, &mut value as *mut u64 as *mut u8, 2);
             value = u64::from_le(value);
-            *ptr = ptr.offset(2);
+            *ptr = ptr.add(2);
         }
         2 => {
             ptr::copy_nonoverlapping(*ptr, &mut value as *mut u64 as *mut u8, 3);
             value = u64::from_le(value);
-            *ptr = ptr.offset(3);
+            *ptr = ptr.add(3);
         }
         3 => {
             ptr::copy_nonoverlapping(*ptr, &mut value as *mut u64 as *mut u8, 4);
             value = u64::from_le(value);
-            *ptr = ptr.offset(4);
+            *ptr = ptr.add(4);
         }
         4 => {
             ptr::copy_nonoverlapping(*ptr, &mut value as *mut u64 as *mut u8, 5);
             value = u64::from_le(value);
-            *ptr = ptr.offset(5);
+            *ptr = ptr.add(5);
         }
         5 => {
             ptr::copy_nonoverlapping(*ptr, &mut value as *mut u64 as *mut u8, 6);
             value = u64::from_le(value);
-            *ptr = ptr.offset(6);
+            *ptr = ptr.add(6);
         }
         6 => {
             ptr::copy_nonoverlapping(*ptr, &mut value as *mut u64 as *mut u8, 7);
             value = u64::from_le(value);
-            *ptr = ptr.offset(7);
+            *ptr = ptr.add(7);
         }
         _ => {
             ptr::copy_nonoverlapping(*ptr, &mut value as *mut u64 as *mut u8, 8);
             value = u64::from_le(value);
-            *ptr = ptr.offset(8);
+            *ptr = ptr.add(8);
         }
     }
     value
 }
 
+/// Decodes `output.len()` values from separate key and data buffers, returning the number of data
+/// bytes read.
+///
+/// # Safety
+///
+/// `keys` must be at least `keys_len(output.len())` bytes long, and `data` must hold all of the
+/// encoded data.
 pub unsafe fn decode_scalar(output: &mut [u64], keys: &[u8], data: &[u8]) -> usize {
+    decode_scalar_with(output, keys, data, &mut Plain)
+}
+
+unsafe fn decode_scalar_with<T>(
+    output: &mut [u64],
+    keys: &[u8],
+    data: &[u8],
+    transform: &mut T,
+) -> usize
+where
+    T: Transform,
+{
+    decode_scalar_alphabet::<Standard, T>(output, keys, data, transform)
+}
+
+unsafe fn decode_scalar_alphabet<A, T>(
+    output: &mut [u64],
+    keys: &[u8],
+    data: &[u8],
+    transform: &mut T,
+) -> usize
+where
+    A: Codes,
+    T: Transform,
+{
     debug_assert!(keys.len() >= keys_len(output.len()));
 
     if output.is_empty() {
@@ -286,17 +296,17 @@ pub unsafe fn decode_scalar(output: &mut [u64], keys: &[u8], data: &[u8]) -> usi
     let mut key = 0;
     ptr::copy_nonoverlapping(keyptr, &mut key as *mut u32 as *mut u8, 3);
     key = u32::from_le(key);
-    keyptr = keyptr.offset(3);
+    keyptr = keyptr.add(3);
 
     for output in output {
         if shift == 24 {
             shift = 0;
             ptr::copy_nonoverlapping(keyptr, &mut key as *mut u32 as *mut u8, 3);
             key = u32::from_le(key);
-            keyptr = keyptr.offset(3);
+            keyptr = keyptr.add(3);
         }
         let code = (key >> shift) & 0b111;
-        *output = decode_single(&mut dataptr, code as u8);
+        *output = transform.decode(A::decode_single(&mut dataptr, code as u8));
         shift += 3;
     }
 
@@ -305,99 +315,181 @@ pub unsafe fn decode_scalar(output: &mut [u64], keys: &[u8], data: &[u8]) -> usi
     read
 }
 
-#[target_feature(enable = "avx2")]
-unsafe fn decode_block_avx(ptr: &mut *const u8, code: u32) -> __m256i {
-    let len = tables::LENGTH[code as usize];
-
-    let data = _mm256_loadu_si256(*ptr as *const __m256i);
-
-    let shuffle1 = tables::DECODE_SHUFFLE_1[code as usize].v;
-    let data1 = _mm256_shuffle_epi8(data, shuffle1);
-
-    let shuffle2 = tables::DECODE_SHUFFLE_2[code as usize].v;
-    let shuffled2 = _mm256_shuffle_epi8(data, shuffle2);
-    let data2 = _mm256_permute4x64_epi64(shuffled2, 0b01001111);
-
-    let data = _mm256_or_si256(data1, data2);
-
-    *ptr = ptr.offset(len as isize);
-    data.into()
+/// Like `decode_scalar`, but reads each value with a single unaligned 8 byte load and a mask.
+///
+/// This is the fastest decoder available on targets without SIMD support.
+///
+/// # Safety
+///
+/// `keys` must be at least `keys_len(output.len())` bytes long, and `data` must hold all of the
+/// encoded data.
+pub unsafe fn decode_swar(output: &mut [u64], keys: &[u8], data: &[u8]) -> usize {
+    decode_swar_with(output, keys, data, &mut Plain)
 }
 
-#[target_feature(enable = "avx2")]
-pub unsafe fn decode_avx(output: &mut [u64], keys: &[u8], data: &[u8]) -> usize {
-    let keys_len = keys_len(output.len());
-    debug_assert!(keys.len() >= keys_len);
+unsafe fn decode_swar_with<T>(
+    output: &mut [u64],
+    keys: &[u8],
+    data: &[u8],
+    transform: &mut T,
+) -> usize
+where
+    T: Transform,
+{
+    debug_assert!(keys.len() >= keys_len(output.len()));
 
-    let mut outptr = output.as_mut_ptr();
-    let mut keyptr = keys.as_ptr();
     let mut dataptr = data.as_ptr();
+    let dataend = data.as_ptr().add(data.len());
 
-    // since the avx codepath loads a full 64 bytes per iteration, we need to make sure to not load
-    // past the end of `data`. The worst case is if each value is 1 byte, in which case we read the
-    // final 8 bytes of real data, and 56 bytes past the end. If we conservatively always take the
-    // scalar path for the last 56 values, we're good.
-    let block_loadable = output.len().saturating_sub(56);
-    let iters = block_loadable / 8;
-    for _ in 0..iters {
+    // a key group consumes at most 64 bytes, and no load reaches past the end of the group's
+    // worst case extent, so the loads are in bounds as long as there are 64 bytes left.
+    let groups = output.len() / 8;
+    let mut count = 0;
+    while count < groups && dataend as usize - dataptr as usize >= 64 {
         let mut key = 0u32;
-        ptr::copy_nonoverlapping(keyptr, &mut key as *mut u32 as *mut u8, 3);
-        keyptr = keyptr.offset(3);
-
-        debug_assert!(dataptr.offset(32) <= data.as_ptr().offset(data.len() as isize));
-        let values = decode_block_avx(&mut dataptr, key & ((1 << 12) - 1));
-        _mm256_storeu_si256(outptr as *mut __m256i, values);
-        outptr = outptr.offset(4);
-
-        debug_assert!(dataptr.offset(32) <= data.as_ptr().offset(data.len() as isize));
-        let values = decode_block_avx(&mut dataptr, key >> 12);
-        _mm256_storeu_si256(outptr as *mut __m256i, values);
-        outptr = outptr.offset(4);
+        ptr::copy_nonoverlapping(
+            keys.as_ptr().add(count * 3),
+            &mut key as *mut u32 as *mut u8,
+            3,
+        );
+        let key = u32::from_le(key);
+
+        for i in 0..8 {
+            let code = (key >> (3 * i)) & 0b111;
+            let value = u64::from_le(ptr::read_unaligned(dataptr as *const u64));
+            let value = value & (u64::MAX >> (56 - 8 * code));
+            *output.get_unchecked_mut(count * 8 + i) = transform.decode(value);
+            dataptr = dataptr.add(code as usize + 1);
+        }
+
+        count += 1;
     }
 
     let read = dataptr as usize - data.as_ptr() as usize;
-    let output = slice::from_raw_parts_mut(outptr, output.len() - iters * 8);
-    let keys = slice::from_raw_parts(
-        keyptr,
-        keys.as_ptr().offset(keys.len() as isize) as usize - keyptr as usize,
-    );
-    let data = slice::from_raw_parts(
-        dataptr,
-        data.as_ptr().offset(data.len() as isize) as usize - dataptr as usize,
-    );
-
-    decode_scalar(output, keys, data) + read
+    read + decode_scalar_with(
+        &mut output[count * 8..],
+        &keys[count * 3..],
+        &data[read..],
+        transform,
+    )
 }
 
 pub fn encode(input: &[u64], buf: &mut [u8]) -> usize {
-    unsafe {
-        assert!(buf.len() >= max_compressed_len(input.len()));
-        let keys_len = keys_len(input.len());
-        let (keys, data) = buf.split_at_mut(keys_len);
-
-        let written = if is_x86_feature_detected!("avx2") {
-            encode_avx(input, keys, data)
-        } else {
-            encode_scalar(input, keys, data)
-        };
+    Engine::new().encode(input, buf)
+}
+
+fn encode_with<T>(backend: Backend, input: &[u64], buf: &mut [u8], transform: &mut T) -> usize
+where
+    T: Transform,
+{
+    assert!(buf.len() >= max_compressed_len(input.len()));
+    let keys_len = keys_len(input.len());
+    let (keys, data) = buf.split_at_mut(keys_len);
+
+    let written = unsafe { backend.encode_with(input, keys, data, transform) };
+
+    keys_len + written
+}
+
+/// Encodes `input` into a new `Vec`.
+pub fn encode_to_vec(input: &[u64]) -> Vec<u8> {
+    let mut buf = vec![];
+    encode_append(input, &mut buf);
+    buf
+}
+
+/// Encodes `input` onto the end of `buf`, returning the number of bytes appended.
+pub fn encode_append(input: &[u64], buf: &mut Vec<u8>) -> usize {
+    Engine::new().encode_append(input, buf)
+}
+
+fn encoded_len_single(value: u64) -> usize {
+    cmp::max(1, 8 - value.leading_zeros() as usize / 8)
+}
+
+/// Returns the exact number of bytes `encode` writes for `input`, without encoding it.
+pub fn encoded_len(input: &[u64]) -> usize {
+    Engine::new().encoded_len(input)
+}
 
-        keys_len + written
+fn encoded_data_len(backend: Backend, input: &[u64]) -> usize {
+    match backend {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Backend::Avx2 => unsafe { avx2::encoded_data_len_avx(input) },
+        _ => input.iter().map(|&v| encoded_len_single(v)).sum(),
     }
 }
 
+/// Encodes `input` into `buf`, returning the number of bytes written.
+///
+/// Unlike `encode`, `buf` only needs to be as large as the encoded output rather than
+/// `max_compressed_len(input.len())`.
+pub fn try_encode(input: &[u64], buf: &mut [u8]) -> Result<usize, EncodeError> {
+    Engine::new().try_encode(input, buf)
+}
+
 pub fn decode(output: &mut [u64], buf: &[u8]) -> usize {
+    Engine::new().decode(output, buf)
+}
+
+fn decode_with<T>(backend: Backend, output: &mut [u64], buf: &[u8], transform: &mut T) -> usize
+where
+    T: Transform,
+{
     unsafe {
         let keys_len = keys_len(output.len());
         let (keys, data) = buf.split_at(keys_len);
-        let data_len = compressed_data_len(output.len(), keys);
+        let data_len = match alphabet::data_len::<Standard>(backend, output.len(), keys) {
+            Ok(len) => len,
+            Err(e) => panic!("{}", e),
+        };
         assert!(data.len() >= data_len, "{} < {}", data.len(), data_len);
 
-        if is_x86_feature_detected!("avx2") {
-            decode_avx(output, keys, data)
-        } else {
-            decode_scalar(output, keys, data)
-        }
+        backend.decode_with(output, keys, data, transform)
+    }
+}
+
+// like `decode_with`, but returns an error instead of panicking, along with the total number of
+// bytes consumed rather than just the data length
+fn try_decode_with<T>(
+    backend: Backend,
+    output: &mut [u64],
+    buf: &[u8],
+    transform: &mut T,
+) -> Result<usize, DecodeError>
+where
+    T: Transform,
+{
+    let keys_len = check_keys_len(output.len(), buf.len())?;
+    let (keys, data) = buf.split_at(keys_len);
+    let data_len = alphabet::data_len::<Standard>(backend, output.len(), keys)?;
+    if data.len() < data_len {
+        return Err(DecodeError::DataTruncated {
+            needed: data_len,
+            available: data.len(),
+        });
     }
+
+    let read = unsafe { backend.decode_with(output, keys, &data[..data_len], transform) };
+    debug_assert_eq!(read, data_len);
+    Ok(keys_len + read)
+}
+
+/// Decodes `output.len()` values from `buf`, returning the total number of bytes consumed.
+///
+/// Unlike `decode`, this never panics or reads out of bounds, no matter what `buf` contains.
+pub fn try_decode(output: &mut [u64], buf: &[u8]) -> Result<usize, DecodeError> {
+    Engine::new().try_decode(output, buf)
+}
+
+/// Decodes `count` values from `buf` into a new `Vec`.
+pub fn decode_to_vec(buf: &[u8], count: usize) -> Result<Vec<u64>, DecodeError> {
+    // make sure the keys are actually there before allocating space for `count` values
+    check_keys_len(count, buf.len())?;
+
+    let mut output = vec![0; count];
+    try_decode(&mut output, buf)?;
+    Ok(output)
 }
 
 #[cfg(test)]
@@ -406,9 +498,7 @@ mod test {
 
     #[test]
     fn check_compressed_len() {
-        let values = (0..4090)
-            .map(|v| v * (u64::max_value() / 4090))
-            .collect::<Vec<_>>();
+        let values = (0..4090).map(|v| v * (u64::MAX / 4090)).collect::<Vec<_>>();
         let len = max_compressed_len(values.len());
         let mut buf = vec![0; len];
         let written = encode(&values, &mut buf);
@@ -417,10 +507,22 @@ mod test {
     }
 
     #[test]
-    fn base_round_trip() {
-        let values = (0..4090)
-            .map(|v| v * (u64::max_value() / 4090))
+    fn check_compressed_len_tails() {
+        let values = (0..80u64)
+            .map(|v| (v * 0x0123_4567_89ab_cdef) >> (v % 8 * 8))
             .collect::<Vec<_>>();
+        let mut buf = vec![0; max_compressed_len(values.len())];
+        for len in 0..=values.len() {
+            let values = &values[..len];
+            let written = encode(values, &mut buf);
+            let data_len = compressed_data_len(values.len(), &buf);
+            assert_eq!(written, keys_len(values.len()) + data_len, "{} values", len);
+        }
+    }
+
+    #[test]
+    fn base_round_trip() {
+        let values = (0..4090).map(|v| v * (u64::MAX / 4090)).collect::<Vec<_>>();
         let len = max_compressed_len(values.len());
         let mut buf = vec![0; len];
         let written = encode(&values, &mut buf);
@@ -443,9 +545,7 @@ mod test {
     #[test]
     fn scalar_round_trip() {
         unsafe {
-            let values = (0..4090)
-                .map(|v| v * (u64::max_value() / 4090))
-                .collect::<Vec<_>>();
+            let values = (0..4090).map(|v| v * (u64::MAX / 4090)).collect::<Vec<_>>();
             let mut keys = vec![0; keys_len(values.len())];
             let mut data = vec![0; values.len() * 8];
 
@@ -458,6 +558,26 @@ mod test {
     }
 
     #[test]
+    fn swar_round_trip() {
+        unsafe {
+            for count in 0..100 {
+                let values = (0..count)
+                    .map(|v| (v * 0x0123_4567_89ab_cdef) >> (v * 3 % 8 * 8))
+                    .collect::<Vec<_>>();
+                let mut keys = vec![0; keys_len(values.len())];
+                let mut data = vec![0; values.len() * 8];
+                let written = encode_scalar(&values, &mut keys, &mut data);
+
+                let mut out = vec![0; values.len()];
+                let read = decode_swar(&mut out, &keys, &data[..written]);
+                assert_eq!(read, written);
+                assert_eq!(values, out);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     fn match_encode() {
         unsafe {
             let values = [
@@ -484,6 +604,168 @@ mod test {
         }
     }
 
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn match_table_free_encode() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+
+        let values = (0..1003)
+            .map(|v: u64| v.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (v * 3 % 9 * 8).min(63))
+            .collect::<Vec<_>>();
+
+        unsafe {
+            let mut keys1 = vec![0; keys_len(values.len())];
+            let mut data1 = vec![0; values.len() * 8];
+            let written1 = encode_scalar(&values, &mut keys1, &mut data1);
+
+            let mut keys2 = vec![0; keys_len(values.len())];
+            let mut data2 = vec![0; values.len() * 8];
+            let written2 = encode_avx_table_free(&values, &mut keys2, &mut data2);
+
+            assert_eq!(keys1, keys2);
+            assert_eq!(written1, written2);
+            assert_eq!(data1[..written1], data2[..written2]);
+        }
+    }
+
+    #[test]
+    fn try_decode_truncated() {
+        let values = (0..100).map(|v| v * (u64::MAX / 100)).collect::<Vec<_>>();
+        let mut buf = vec![0; max_compressed_len(values.len())];
+        let written = encode(&values, &mut buf);
+        let keys_len = keys_len(values.len());
+
+        let mut out = vec![0; values.len()];
+        assert_eq!(try_decode(&mut out, &buf[..written]), Ok(written));
+        assert_eq!(values, out);
+
+        for len in 0..written {
+            let err = try_decode(&mut out, &buf[..len]).unwrap_err();
+            let expected = if len < keys_len {
+                DecodeError::KeysTruncated {
+                    needed: keys_len,
+                    available: len,
+                }
+            } else {
+                DecodeError::DataTruncated {
+                    needed: written - keys_len,
+                    available: len - keys_len,
+                }
+            };
+            assert_eq!(err, expected);
+        }
+    }
+
+    #[test]
+    fn absurd_count() {
+        let err = DecodeError::KeysTruncated {
+            needed: usize::MAX,
+            available: 3,
+        };
+        assert_eq!(
+            try_compressed_data_len(usize::MAX, &[0; 3]),
+            Err(err.clone())
+        );
+        assert_eq!(decode_to_vec(&[0; 3], usize::MAX), Err(err));
+        assert_eq!(
+            decode_to_vec(&[0; 3], usize::MAX - 7),
+            Err(DecodeError::KeysTruncated {
+                needed: (usize::MAX - 7) / 8 * 3,
+                available: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn try_decode_garbage() {
+        let buf = (0..1024).map(|i| (i * 37 % 251) as u8).collect::<Vec<_>>();
+        for count in 0..200 {
+            let mut out = vec![0; count];
+            for len in (0..buf.len()).step_by(13) {
+                let _ = try_decode(&mut out, &buf[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn try_encode_exact() {
+        let values = (0..100)
+            .map(|v| (v * 0x0123_4567_89ab_cdef) >> (v % 8 * 8))
+            .collect::<Vec<_>>();
+        let mut expected = vec![0; max_compressed_len(values.len())];
+        let len = encode(&values, &mut expected);
+        expected.truncate(len);
+
+        let mut buf = vec![0xff; len];
+        assert_eq!(try_encode(&values, &mut buf), Ok(len));
+        assert_eq!(buf, expected);
+
+        let mut buf = vec![0; len - 1];
+        assert_eq!(
+            try_encode(&values, &mut buf),
+            Err(EncodeError::BufferTooSmall {
+                needed: len,
+                available: len - 1,
+            })
+        );
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn match_keys_data_len() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+
+        let keys = (0..3000u32)
+            .map(|i| (i.wrapping_mul(0x9e37_79b9) >> 13) as u8)
+            .collect::<Vec<_>>();
+        for groups in 0..1000 {
+            let keys = &keys[..groups * 3];
+            assert_eq!(
+                unsafe { avx2::keys_data_len_avx(keys) },
+                keys_data_len_scalar::<Standard>(keys)
+            );
+        }
+        assert_eq!(keys_data_len_scalar::<Standard>(&[0xff; 96]), 32 * 64);
+    }
+
+    #[test]
+    fn exact_encoded_len() {
+        let values = (0..1000u64)
+            .map(|v| v.wrapping_mul(0x0123_4567_89ab_cdef) >> (v % 9 * 8).min(63))
+            .collect::<Vec<_>>();
+        let mut buf = vec![0; max_compressed_len(values.len())];
+        for len in 0..values.len() {
+            let values = &values[..len];
+            assert_eq!(encoded_len(values), encode(values, &mut buf));
+            let expected = values.iter().map(|&v| encoded_len_single(v)).sum::<usize>();
+            for backend in [Backend::Scalar, Backend::Avx2] {
+                if backend.is_supported() {
+                    assert_eq!(encoded_data_len(backend, values), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn vec_round_trip() {
+        let values = (0..4090).map(|v| v * (u64::MAX / 4090)).collect::<Vec<_>>();
+        let buf = encode_to_vec(&values);
+        let mut expected = vec![0; max_compressed_len(values.len())];
+        let len = encode(&values, &mut expected);
+        assert_eq!(buf, &expected[..len]);
+        assert_eq!(decode_to_vec(&buf, values.len()).unwrap(), values);
+
+        let mut buf = vec![1, 2, 3];
+        let written = encode_append(&values, &mut buf);
+        assert_eq!(buf.len(), written + 3);
+        assert_eq!(&buf[..3], &[1, 2, 3]);
+        assert_eq!(decode_to_vec(&buf[3..], values.len()).unwrap(), values);
+    }
+
     #[test]
     fn single_round_trip() {
         let tests = [
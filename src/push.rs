@@ -0,0 +1,221 @@
+use crate::engine::{Backend, Engine};
+use crate::index::{code_len, group_len, read_key};
+use crate::keys_len;
+use crate::transform::Plain;
+
+/// The result of a call to `Decoder::feed`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Progress {
+    /// The number of bytes of input consumed.
+    pub consumed: usize,
+    /// The number of values written to the output.
+    pub written: usize,
+    /// Whether all values have been decoded.
+    pub finished: bool,
+}
+
+/// A decoder which accepts its input in arbitrary fragments.
+///
+/// Keys are buffered until all of them have arrived, after which data is decoded a key group at
+/// a time. Only the data of a key group split across fragments is copied.
+pub struct Decoder {
+    backend: Backend,
+    count: usize,
+    keys: Vec<u8>,
+    group: usize,
+    partial: [u8; 64],
+    partial_len: usize,
+}
+
+impl Decoder {
+    /// Creates a decoder for a buffer holding `count` values.
+    pub fn new(count: usize) -> Decoder {
+        Decoder::with_engine(count, &Engine::new())
+    }
+
+    /// Creates a decoder for a buffer holding `count` values which decodes with `engine`.
+    pub fn with_engine(count: usize, engine: &Engine) -> Decoder {
+        Decoder {
+            backend: engine.backend(),
+            count,
+            keys: Vec::with_capacity(keys_len(count)),
+            group: 0,
+            partial: [0; 64],
+            partial_len: 0,
+        }
+    }
+
+    /// Returns the number of values decoded so far.
+    pub fn decoded(&self) -> usize {
+        (self.group * 8).min(self.count)
+    }
+
+    /// Returns `true` if all values have been decoded.
+    pub fn is_finished(&self) -> bool {
+        self.decoded() == self.count
+    }
+
+    /// Consumes the next fragment of input, writing decoded values to `output`.
+    ///
+    /// Values are written a key group at a time, so no progress is made if `output` has room for
+    /// fewer than 8 values (or the number remaining, if smaller). Input is not consumed past the
+    /// end of the encoded buffer.
+    pub fn feed(&mut self, mut input: &[u8], output: &mut [u64]) -> Progress {
+        let input_len = input.len();
+        let mut written = 0;
+
+        let keys_len = keys_len(self.count);
+        if self.keys.len() < keys_len {
+            let len = (keys_len - self.keys.len()).min(input.len());
+            self.keys.extend_from_slice(&input[..len]);
+            input = &input[len..];
+        }
+
+        if self.keys.len() == keys_len {
+            loop {
+                if self.partial_len == 0 {
+                    let (groups, len) = self.whole_groups(input.len(), output.len() - written);
+                    if groups != 0 {
+                        written +=
+                            self.decode_groups(groups, &input[..len], &mut output[written..]);
+                        input = &input[len..];
+                    }
+                }
+
+                if self.is_finished() || output.len() - written < self.group_values() {
+                    break;
+                }
+
+                // the next group doesn't fit in the input, so carry its data over to the next call
+                let needed = self.group_data_len(self.group);
+                let len = (needed - self.partial_len).min(input.len());
+                self.partial[self.partial_len..self.partial_len + len]
+                    .copy_from_slice(&input[..len]);
+                self.partial_len += len;
+                input = &input[len..];
+
+                if self.partial_len < needed {
+                    break;
+                }
+
+                let partial = self.partial;
+                written += self.decode_groups(1, &partial[..needed], &mut output[written..]);
+                self.partial_len = 0;
+            }
+        }
+
+        Progress {
+            consumed: input_len - input.len(),
+            written,
+            finished: self.is_finished(),
+        }
+    }
+
+    // returns the number of values in the next group
+    fn group_values(&self) -> usize {
+        (self.count - self.decoded()).min(8)
+    }
+
+    // returns the data length of a group, excluding the padding of a partial group
+    fn group_data_len(&self, group: usize) -> usize {
+        let key = &self.keys[group * 3..group * 3 + 3];
+        let values = (self.count - group * 8).min(8);
+        if values == 8 {
+            group_len(key)
+        } else {
+            let key = read_key(key);
+            (0..values).map(|i| code_len(key, i)).sum()
+        }
+    }
+
+    // returns the number of whole groups starting at the next one which fit in the input and
+    // output, along with the length of their data
+    fn whole_groups(&self, input_len: usize, output_len: usize) -> (usize, usize) {
+        let mut group = self.group;
+        let mut len = 0;
+        while group < self.count.div_ceil(8) {
+            let group_len = self.group_data_len(group);
+            let values = ((group + 1) * 8).min(self.count) - self.decoded();
+            if len + group_len > input_len || values > output_len {
+                break;
+            }
+            len += group_len;
+            group += 1;
+        }
+        (group - self.group, len)
+    }
+
+    fn decode_groups(&mut self, groups: usize, data: &[u8], output: &mut [u64]) -> usize {
+        let values = (self.count - self.decoded()).min(groups * 8);
+        let keys = &self.keys[self.group * 3..(self.group + groups) * 3];
+        unsafe {
+            self.backend
+                .decode_with(&mut output[..values], keys, data, &mut Plain);
+        }
+        self.group += groups;
+        values
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{encode, max_compressed_len};
+
+    fn feed_all(buf: &[u8], count: usize, fragment: usize, chunk: usize) -> Vec<u64> {
+        let mut decoder = Decoder::new(count);
+        let mut out = vec![0; count];
+        let mut written = 0;
+        let mut pos = 0;
+        let mut finished = decoder.is_finished();
+
+        while !finished {
+            let end = (pos + fragment).min(buf.len());
+            let out_end = (written + chunk).min(count);
+            let progress = decoder.feed(&buf[pos..end], &mut out[written..out_end]);
+            pos += progress.consumed;
+            written += progress.written;
+            finished = progress.finished;
+        }
+
+        assert_eq!(pos, buf.len());
+        assert_eq!(written, count);
+        out
+    }
+
+    #[test]
+    fn fragments() {
+        for &count in &[0, 1, 7, 8, 13, 64, 1000] {
+            let values = (0..count as u64)
+                .map(|v| v.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (v % 8 * 8))
+                .collect::<Vec<_>>();
+            let mut buf = vec![0; max_compressed_len(count)];
+            let written = encode(&values, &mut buf);
+            let buf = &buf[..written];
+
+            for &fragment in &[1, 2, 3, 5, 64, 100, 10_000] {
+                for &chunk in &[8, 16, 100, 10_000] {
+                    assert_eq!(feed_all(buf, count, fragment, chunk), values);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_input() {
+        let mut buf = vec![0; max_compressed_len(9)];
+        let written = encode(&[1, 2, 3, 4, 5, 6, 7, 8, 1 << 50], &mut buf);
+
+        let mut out = [0; 9];
+        let progress = Decoder::new(9).feed(&buf, &mut out);
+        assert_eq!(
+            progress,
+            Progress {
+                consumed: written,
+                written: 9,
+                finished: true,
+            }
+        );
+        assert_eq!(out, [1, 2, 3, 4, 5, 6, 7, 8, 1 << 50]);
+    }
+}
@@ -0,0 +1,230 @@
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+use crate::engine::Engine;
+use crate::transform::Transform;
+use crate::zigzag::DeltaZigzag;
+use crate::{decode_with, encode_with, keys_len, max_compressed_len, try_decode_with, DecodeError};
+
+// A frame-of-reference buffer looks like:
+//
+// bases: the minimum of each key group, encoded with zigzag delta encoding
+// residuals: each value minus the base of its key group
+
+/// Subtracts the base of each key group from its values.
+pub struct For<'a> {
+    bases: &'a [u64],
+    pos: usize,
+}
+
+impl<'a> For<'a> {
+    pub fn new(bases: &'a [u64]) -> For<'a> {
+        For { bases, pos: 0 }
+    }
+
+    #[inline]
+    fn next_base(&mut self, values: usize) -> u64 {
+        let base = self.bases[self.pos / 8];
+        self.pos += values;
+        base
+    }
+}
+
+impl Transform for For<'_> {
+    #[inline]
+    fn encode(&mut self, value: u64) -> u64 {
+        value.wrapping_sub(self.next_base(1))
+    }
+
+    #[inline]
+    fn decode(&mut self, value: u64) -> u64 {
+        value.wrapping_add(self.next_base(1))
+    }
+
+    // the kernels only process aligned blocks of 4 values, which never straddle a key group
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx2")]
+    #[inline]
+    unsafe fn encode_avx(&mut self, values: __m256i) -> __m256i {
+        let base = _mm256_set1_epi64x(self.next_base(4) as i64);
+        _mm256_sub_epi64(values, base)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx2")]
+    #[inline]
+    unsafe fn decode_avx(&mut self, values: __m256i) -> __m256i {
+        let base = _mm256_set1_epi64x(self.next_base(4) as i64);
+        _mm256_add_epi64(values, base)
+    }
+}
+
+/// Returns the maximum number of bytes `encode_for` writes for `values` values.
+pub fn max_compressed_len_for(values: usize) -> usize {
+    max_compressed_len(values.div_ceil(8)) + max_compressed_len(values)
+}
+
+/// Encodes `input` into `buf` relative to the minimum of each key group, returning the number of
+/// bytes written.
+///
+/// This is much more compact than `encode` for values clustered around a large base, like
+/// timestamps.
+///
+/// # Panics
+///
+/// Panics if `buf` is shorter than `max_compressed_len_for(input.len())`.
+pub fn encode_for(input: &[u64], buf: &mut [u8]) -> usize {
+    Engine::new().encode_for(input, buf)
+}
+
+/// Decodes values encoded by `encode_for`, returning the number of bytes read.
+///
+/// # Panics
+///
+/// Panics if `buf` is too short to hold `output.len()` encoded values.
+pub fn decode_for(output: &mut [u64], buf: &[u8]) -> usize {
+    Engine::new().decode_for(output, buf)
+}
+
+/// Like `decode_for`, but returns an error rather than panicking if `buf` is truncated.
+pub fn try_decode_for(output: &mut [u64], buf: &[u8]) -> Result<usize, DecodeError> {
+    Engine::new().try_decode_for(output, buf)
+}
+
+impl Engine {
+    /// Like the crate's `encode_for`, using this engine's backend.
+    pub fn encode_for(&self, input: &[u64], buf: &mut [u8]) -> usize {
+        assert!(buf.len() >= max_compressed_len_for(input.len()));
+
+        let bases = input
+            .chunks(8)
+            .map(|group| *group.iter().min().unwrap())
+            .collect::<Vec<_>>();
+        let backend = self.backend();
+        let written = encode_with(backend, &bases, buf, &mut DeltaZigzag::new(0));
+        written + encode_with(backend, input, &mut buf[written..], &mut For::new(&bases))
+    }
+
+    /// Like the crate's `decode_for`, using this engine's backend.
+    pub fn decode_for(&self, output: &mut [u64], buf: &[u8]) -> usize {
+        let backend = self.backend();
+        let mut bases = vec![0; output.len().div_ceil(8)];
+        let bases_data_len = match self.try_compressed_data_len(bases.len(), buf) {
+            Ok(len) => len,
+            Err(e) => panic!("{}", e),
+        };
+        let bases_len = keys_len(bases.len()) + bases_data_len;
+        decode_with(
+            backend,
+            &mut bases,
+            &buf[..bases_len],
+            &mut DeltaZigzag::new(0),
+        );
+
+        let read = decode_with(backend, output, &buf[bases_len..], &mut For::new(&bases));
+        bases_len + keys_len(output.len()) + read
+    }
+
+    /// Like the crate's `try_decode_for`, using this engine's backend.
+    pub fn try_decode_for(&self, output: &mut [u64], buf: &[u8]) -> Result<usize, DecodeError> {
+        let backend = self.backend();
+        let mut bases = vec![0; output.len().div_ceil(8)];
+        let bases_len = try_decode_with(backend, &mut bases, buf, &mut DeltaZigzag::new(0))?;
+        let read = try_decode_with(backend, output, &buf[bases_len..], &mut For::new(&bases))?;
+        Ok(bases_len + read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encode;
+
+    fn timestamps() -> Vec<u64> {
+        (0..4090u64)
+            .map(|v| 1_700_000_000_000 + v / 8 * 60_000 + v * 7919 % 250)
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let values = timestamps();
+        let mut buf = vec![0; max_compressed_len_for(values.len())];
+        let written = encode_for(&values, &mut buf);
+
+        let mut plain = vec![0; max_compressed_len(values.len())];
+        assert!(written * 2 < encode(&values, &mut plain));
+
+        let mut out = vec![0; values.len()];
+        assert_eq!(decode_for(&mut out, &buf[..written]), written);
+        assert_eq!(values, out);
+    }
+
+    #[test]
+    fn try_decode_truncated() {
+        let values = timestamps()[..100].to_vec();
+        let mut buf = vec![0; max_compressed_len_for(values.len())];
+        let written = encode_for(&values, &mut buf);
+
+        let mut out = vec![0; values.len()];
+        assert_eq!(try_decode_for(&mut out, &buf[..written]), Ok(written));
+        assert_eq!(values, out);
+
+        for len in 0..written {
+            assert!(try_decode_for(&mut out, &buf[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn tails() {
+        let values = (0..40u64)
+            .map(|v| u64::MAX - v * v * 1000)
+            .collect::<Vec<_>>();
+        let mut buf = vec![0; max_compressed_len_for(values.len())];
+        for len in 0..=values.len() {
+            let values = &values[..len];
+            let written = encode_for(values, &mut buf);
+
+            let mut out = vec![0; len];
+            assert_eq!(decode_for(&mut out, &buf[..written]), written);
+            assert_eq!(values, &out[..]);
+        }
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn match_scalar() {
+        use crate::avx2::{decode_avx_with, encode_avx_with};
+        use crate::{decode_scalar_with, encode_scalar_with};
+
+        unsafe {
+            let values = timestamps();
+            let bases = values
+                .chunks(8)
+                .map(|group| *group.iter().min().unwrap())
+                .collect::<Vec<_>>();
+            let mut keys1 = vec![0; keys_len(values.len())];
+            let mut data1 = vec![0; values.len() * 8];
+            let written1 =
+                encode_scalar_with(&values, &mut keys1, &mut data1, &mut For::new(&bases));
+
+            let mut keys2 = vec![0; keys_len(values.len())];
+            let mut data2 = vec![0; values.len() * 8];
+            let written2 = encode_avx_with(&values, &mut keys2, &mut data2, &mut For::new(&bases));
+
+            assert_eq!(keys1, keys2);
+            assert_eq!(data1, data2);
+            assert_eq!(written1, written2);
+
+            let mut out1 = vec![0; values.len()];
+            decode_scalar_with(&mut out1, &keys1, &data1, &mut For::new(&bases));
+            let mut out2 = vec![0; values.len()];
+            decode_avx_with(&mut out2, &keys1, &data1, &mut For::new(&bases));
+            assert_eq!(values, out1);
+            assert_eq!(values, out2);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! A 128 bit backend for machines without AVX2.
+//!
+//! Values are handled in pairs, with a single shuffle moving the 2 to 16 bytes of a pair between
+//! its compressed and decompressed forms. The shuffle tables are indexed by the 6 bits of key for
+//! a pair.
+use std::slice;
+
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+use crate::alphabet::{Codes, Standard};
+use crate::tables::PairShuffle;
+use crate::transform::{Plain, Transform};
+use crate::{decode_scalar_with, encode_scalar_with, keys_len};
+
+impl PairShuffle {
+    #[target_feature(enable = "ssse3")]
+    #[inline]
+    pub(crate) unsafe fn load(&self) -> __m128i {
+        _mm_load_si128(self.0.as_ptr() as *const __m128i)
+    }
+}
+
+fn pair_len(code: u32) -> usize {
+    (code & 7) as usize + (code >> 3) as usize + 2
+}
+
+fn code(value: u64) -> u32 {
+    (63 - (value | 1).leading_zeros()) / 8
+}
+
+/// Encodes `input` into separate key and data buffers, returning the number of data bytes written.
+///
+/// # Safety
+///
+/// `keys` must be at least `keys_len(input.len())` bytes long, and `data` must be large enough to
+/// hold the encoded data. The CPU must support SSSE3.
+#[target_feature(enable = "ssse3")]
+pub unsafe fn encode_ssse3(input: &[u64], keys: &mut [u8], data: &mut [u8]) -> usize {
+    encode_ssse3_with(input, keys, data, &mut Plain)
+}
+
+#[target_feature(enable = "ssse3")]
+pub unsafe fn encode_ssse3_with<T>(
+    input: &[u64],
+    keys: &mut [u8],
+    data: &mut [u8],
+    transform: &mut T,
+) -> usize
+where
+    T: Transform,
+{
+    debug_assert!(keys.len() >= keys_len(input.len()));
+
+    let mut dataptr = data.as_mut_ptr();
+    let dataend = data.as_ptr().add(data.len());
+
+    // each pair stores all 16 bytes, so a key group can write up to 64 bytes.
+    let groups = input.len() / 8;
+    let mut count = 0;
+    while count < groups && dataend as usize - dataptr as usize >= 64 {
+        let mut key = 0;
+        for pair in 0..4 {
+            let i = count * 8 + pair * 2;
+            let values = [
+                transform.encode(*input.get_unchecked(i)),
+                transform.encode(*input.get_unchecked(i + 1)),
+            ];
+            let code = code(values[0]) | code(values[1]) << 3;
+
+            let values = _mm_loadu_si128(values.as_ptr() as *const __m128i);
+            let shuffled =
+                _mm_shuffle_epi8(values, Standard::PAIR_ENCODE_SHUFFLE[code as usize].load());
+            _mm_storeu_si128(dataptr as *mut __m128i, shuffled);
+            dataptr = dataptr.add(pair_len(code));
+
+            key |= code << (pair * 6);
+        }
+
+        let key = key.to_le_bytes();
+        keys.get_unchecked_mut(count * 3..count * 3 + 3)
+            .copy_from_slice(&key[..3]);
+        count += 1;
+    }
+
+    let written = dataptr as usize - data.as_ptr() as usize;
+    let data = slice::from_raw_parts_mut(dataptr, data.len() - written);
+    written + encode_scalar_with(&input[count * 8..], &mut keys[count * 3..], data, transform)
+}
+
+/// Decodes `output.len()` values from separate key and data buffers, returning the number of data
+/// bytes read.
+///
+/// # Safety
+///
+/// `keys` must be at least `keys_len(output.len())` bytes long, and `data` must hold all of the
+/// encoded data. The CPU must support SSSE3.
+#[target_feature(enable = "ssse3")]
+pub unsafe fn decode_ssse3(output: &mut [u64], keys: &[u8], data: &[u8]) -> usize {
+    decode_ssse3_with(output, keys, data, &mut Plain)
+}
+
+#[target_feature(enable = "ssse3")]
+pub unsafe fn decode_ssse3_with<T>(
+    output: &mut [u64],
+    keys: &[u8],
+    data: &[u8],
+    transform: &mut T,
+) -> usize
+where
+    T: Transform,
+{
+    debug_assert!(keys.len() >= keys_len(output.len()));
+
+    // each pair loads 16 bytes but may only consume 2 of them. Leaving the last 16 values to the
+    // scalar path guarantees that there are always 16 real bytes left to load.
+    let groups = output.len().saturating_sub(16) / 8;
+
+    let mut dataptr = data.as_ptr();
+    for group in 0..groups {
+        let key = keys.get_unchecked(group * 3..group * 3 + 3);
+        let key = key[0] as u32 | (key[1] as u32) << 8 | (key[2] as u32) << 16;
+
+        for pair in 0..4 {
+            let code = key >> (pair * 6) & 0x3f;
+
+            debug_assert!(dataptr.add(16) <= data.as_ptr().add(data.len()));
+            let values = _mm_loadu_si128(dataptr as *const __m128i);
+            let shuffled =
+                _mm_shuffle_epi8(values, Standard::PAIR_DECODE_SHUFFLE[code as usize].load());
+            let outptr = output.as_mut_ptr().add(group * 8 + pair * 2);
+            _mm_storeu_si128(outptr as *mut __m128i, shuffled);
+            *outptr = transform.decode(*outptr);
+            *outptr.add(1) = transform.decode(*outptr.add(1));
+
+            dataptr = dataptr.add(pair_len(code));
+        }
+    }
+
+    let read = dataptr as usize - data.as_ptr() as usize;
+    read + decode_scalar_with(
+        &mut output[groups * 8..],
+        &keys[groups * 3..],
+        &data[read..],
+        transform,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::delta::Delta;
+    use crate::{decode_scalar, encode_scalar};
+
+    fn values() -> Vec<u64> {
+        (0..4090)
+            .map(|v| (v * (u64::MAX / 4090)) >> (v % 8 * 8))
+            .collect()
+    }
+
+    #[test]
+    fn match_scalar() {
+        unsafe {
+            for &count in &[0, 1, 8, 15, 16, 17, 24, 100, 4090] {
+                let values = &values()[..count];
+                let mut keys1 = vec![0; keys_len(values.len())];
+                let mut data1 = vec![0; values.len() * 8];
+                let written1 = encode_scalar(values, &mut keys1, &mut data1);
+
+                let mut keys2 = vec![0; keys_len(values.len())];
+                let mut data2 = vec![0; written1];
+                let written2 = encode_ssse3(values, &mut keys2, &mut data2);
+
+                assert_eq!(keys1, keys2);
+                assert_eq!(&data1[..written1], &data2[..]);
+                assert_eq!(written1, written2);
+
+                let mut out1 = vec![0; values.len()];
+                decode_scalar(&mut out1, &keys1, &data1[..written1]);
+                let mut out2 = vec![0; values.len()];
+                let read = decode_ssse3(&mut out2, &keys1, &data1[..written1]);
+                assert_eq!(read, written1);
+                assert_eq!(values, &out1[..]);
+                assert_eq!(values, &out2[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn transform_round_trip() {
+        unsafe {
+            let values = (0..4090).map(|v| v * 1000).collect::<Vec<_>>();
+            let mut keys = vec![0; keys_len(values.len())];
+            let mut data = vec![0; values.len() * 8];
+            let written = encode_ssse3_with(&values, &mut keys, &mut data, &mut Delta::new(0));
+            assert!(written < values.len() * 2);
+
+            let mut out = vec![0; values.len()];
+            decode_ssse3_with(&mut out, &keys, &data[..written], &mut Delta::new(0));
+            assert_eq!(values, out);
+        }
+    }
+}
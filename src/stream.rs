@@ -0,0 +1,442 @@
+use std::io::{self, Read, Write};
+use std::slice;
+
+use crate::frame::{read_varint, write_varint};
+use crate::{
+    checked_keys_len, decode, encode, keys_len, max_compressed_len, try_compressed_data_len,
+};
+
+// A stream is a sequence of self-contained chunks, each of which looks like:
+//
+// count: varint
+// data length: varint
+// keys: [u8; keys_len(count)]
+// data: [u8; data length]
+//
+// Every chunk but the last holds a multiple of 8 values.
+const DEFAULT_CHUNK_LEN: usize = 4096;
+// the longest chunk a decoder accepts by default, which bounds how much memory a corrupt header can
+// make it allocate
+const DEFAULT_MAX_CHUNK_LEN: usize = 1 << 20;
+
+/// An encoder which writes values to an underlying writer incrementally.
+///
+/// Values are buffered until a full chunk is available, which is then encoded and written out.
+/// `finish` should be called to write the final partial chunk. Dropping the encoder writes it as
+/// well, but any error doing so is ignored.
+pub struct StreamEncoder<W: Write> {
+    // only taken by `finish`
+    writer: Option<W>,
+    chunk_len: usize,
+    values: Vec<u64>,
+    header: Vec<u8>,
+    body: Vec<u8>,
+}
+
+impl<W: Write> StreamEncoder<W> {
+    /// Creates a new encoder writing to `writer`.
+    pub fn new(writer: W) -> StreamEncoder<W> {
+        StreamEncoder::with_chunk_len(writer, DEFAULT_CHUNK_LEN)
+    }
+
+    /// Creates a new encoder writing chunks of `chunk_len` values to `writer`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_len` is 0 or not a multiple of 8.
+    pub fn with_chunk_len(writer: W, chunk_len: usize) -> StreamEncoder<W> {
+        assert!(
+            chunk_len != 0 && chunk_len.is_multiple_of(8),
+            "chunk length must be a nonzero multiple of 8"
+        );
+
+        StreamEncoder {
+            writer: Some(writer),
+            chunk_len,
+            values: Vec::with_capacity(chunk_len),
+            header: vec![],
+            body: vec![0; max_compressed_len(chunk_len)],
+        }
+    }
+
+    /// Returns a shared reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.writer.as_ref().unwrap()
+    }
+
+    /// Returns a mutable reference to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.writer.as_mut().unwrap()
+    }
+
+    /// Adds a value to the stream.
+    pub fn push(&mut self, value: u64) -> io::Result<()> {
+        self.values.push(value);
+        if self.values.len() == self.chunk_len {
+            self.write_chunk()?;
+        }
+        Ok(())
+    }
+
+    /// Adds values to the stream.
+    pub fn extend(&mut self, mut values: &[u64]) -> io::Result<()> {
+        while !values.is_empty() {
+            let len = (self.chunk_len - self.values.len()).min(values.len());
+            self.values.extend_from_slice(&values[..len]);
+            values = &values[len..];
+
+            if self.values.len() == self.chunk_len {
+                self.write_chunk()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes any buffered values and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.values.is_empty() {
+            self.write_chunk()?;
+        }
+        Ok(self.writer.take().unwrap())
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        // full key groups go through the SIMD kernels, and encode falls back to the scalar
+        // path for the partial tail of the last chunk
+        let written = encode(&self.values, &mut self.body);
+
+        self.header.clear();
+        write_varint(&mut self.header, self.values.len() as u64);
+        write_varint(
+            &mut self.header,
+            (written - keys_len(self.values.len())) as u64,
+        );
+
+        // clear the values first so a failed write isn't retried on drop
+        self.values.clear();
+        let writer = self.writer.as_mut().unwrap();
+        writer.write_all(&self.header)?;
+        writer.write_all(&self.body[..written])?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for StreamEncoder<W> {
+    fn drop(&mut self) {
+        if self.writer.is_some() && !self.values.is_empty() {
+            let _ = self.write_chunk();
+        }
+    }
+}
+
+/// A decoder which reads values written by a `StreamEncoder` from an underlying reader.
+///
+/// Chunks are read and decoded one at a time, so memory use is bounded by the chunk size rather
+/// than the length of the stream. Headers are read a byte at a time, so unbuffered readers should
+/// be wrapped in a `BufReader`.
+///
+/// The position in the stream is lost once an error has been returned, so after the first error
+/// the iterator returns `None` and `read_values` returns 0.
+pub struct StreamDecoder<R> {
+    reader: R,
+    max_chunk_len: usize,
+    values: Vec<u64>,
+    pos: usize,
+    buf: Vec<u8>,
+    failed: bool,
+}
+
+impl<R: Read> StreamDecoder<R> {
+    /// Creates a new decoder reading from `reader`.
+    ///
+    /// Chunks of more than 2^20 values are rejected.
+    pub fn new(reader: R) -> StreamDecoder<R> {
+        StreamDecoder::with_max_chunk_len(reader, DEFAULT_MAX_CHUNK_LEN)
+    }
+
+    /// Creates a new decoder reading from `reader` which rejects chunks of more than
+    /// `max_chunk_len` values.
+    pub fn with_max_chunk_len(reader: R, max_chunk_len: usize) -> StreamDecoder<R> {
+        StreamDecoder {
+            reader,
+            max_chunk_len,
+            values: vec![],
+            pos: 0,
+            buf: vec![],
+            failed: false,
+        }
+    }
+
+    /// Returns a shared reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Returns a mutable reference to the underlying reader.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Consumes the decoder, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads values into `output`, returning the number of values read.
+    ///
+    /// Fewer than `output.len()` values are only returned at the end of the stream.
+    pub fn read_values(&mut self, output: &mut [u64]) -> io::Result<usize> {
+        let mut read = 0;
+        while read < output.len() {
+            if !self.fill()? {
+                break;
+            }
+
+            let len = (self.values.len() - self.pos).min(output.len() - read);
+            output[read..read + len].copy_from_slice(&self.values[self.pos..self.pos + len]);
+            self.pos += len;
+            read += len;
+        }
+        Ok(read)
+    }
+
+    // reads chunks until there are values available, returning false at the end of the stream or
+    // after an earlier error
+    fn fill(&mut self) -> io::Result<bool> {
+        while self.pos == self.values.len() {
+            if self.failed {
+                return Ok(false);
+            }
+            match self.read_chunk() {
+                Ok(true) => {}
+                Ok(false) => return Ok(false),
+                Err(e) => {
+                    self.failed = true;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // returns false at the end of the stream
+    fn read_chunk(&mut self) -> io::Result<bool> {
+        let count = match self.read_varint(true)? {
+            Some(count) => count,
+            None => return Ok(false),
+        };
+        let data_len = self.read_varint(false)?.unwrap();
+        if count > self.max_chunk_len as u64 || data_len > usize::MAX as u64 {
+            return Err(invalid_data("chunk too large"));
+        }
+        let count = count as usize;
+        let data_len = data_len as usize;
+
+        // read through a Take rather than preallocating so a corrupt header can't make us
+        // allocate more than the stream actually contains
+        let len = checked_keys_len(count)
+            .and_then(|keys_len| keys_len.checked_add(data_len))
+            .ok_or_else(|| invalid_data("chunk too large"))?;
+        self.buf.clear();
+        (&mut self.reader)
+            .take(len as u64)
+            .read_to_end(&mut self.buf)?;
+        if self.buf.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "stream ended mid-chunk",
+            ));
+        }
+
+        let needed = try_compressed_data_len(count, &self.buf).map_err(invalid_data)?;
+        if needed != data_len {
+            return Err(invalid_data("chunk data length mismatch"));
+        }
+
+        // chunks are decoded independently, so the scalar tail of the SIMD decoders never
+        // reaches across a chunk boundary
+        self.values.clear();
+        self.values.resize(count, 0);
+        decode(&mut self.values, &self.buf);
+        self.pos = 0;
+
+        Ok(true)
+    }
+
+    fn read_varint(&mut self, start: bool) -> io::Result<Option<u64>> {
+        let mut buf = [0; 10];
+        for (i, byte) in buf.iter_mut().enumerate() {
+            if let Err(e) = self.reader.read_exact(slice::from_mut(byte)) {
+                if start && i == 0 && e.kind() == io::ErrorKind::UnexpectedEof {
+                    return Ok(None);
+                }
+                return Err(e);
+            }
+            if *byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        read_varint(&mut &buf[..]).map(Some).map_err(invalid_data)
+    }
+}
+
+impl<R: Read> Iterator for StreamDecoder<R> {
+    type Item = io::Result<u64>;
+
+    fn next(&mut self) -> Option<io::Result<u64>> {
+        match self.fill() {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => return Some(Err(e)),
+        }
+
+        let value = self.values[self.pos];
+        self.pos += 1;
+        Some(Ok(value))
+    }
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode_to_vec;
+
+    fn read_chunks(mut buf: &[u8]) -> Vec<Vec<u64>> {
+        let mut chunks = vec![];
+        while !buf.is_empty() {
+            let count = read_varint(&mut buf).unwrap() as usize;
+            let data_len = read_varint(&mut buf).unwrap() as usize;
+            let len = keys_len(count) + data_len;
+            chunks.push(decode_to_vec(&buf[..len], count).unwrap());
+            buf = &buf[len..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn chunks() {
+        let values = (0..1000u64).map(|v| v << (v % 57)).collect::<Vec<_>>();
+
+        let mut encoder = StreamEncoder::with_chunk_len(vec![], 64);
+        encoder.extend(&values[..500]).unwrap();
+        for &value in &values[500..] {
+            encoder.push(value).unwrap();
+        }
+        let buf = encoder.finish().unwrap();
+
+        let chunks = read_chunks(&buf);
+        assert_eq!(chunks.len(), 16);
+        assert!(chunks[..15].iter().all(|c| c.len() == 64));
+        assert_eq!(chunks.concat(), values);
+    }
+
+    #[test]
+    fn drop_flushes() {
+        let mut buf = vec![];
+        let mut encoder = StreamEncoder::with_chunk_len(&mut buf, 64);
+        encoder.extend(&[1, 2, 3]).unwrap();
+        drop(encoder);
+
+        assert_eq!(read_chunks(&buf), [vec![1, 2, 3]]);
+    }
+
+    #[test]
+    fn empty() {
+        let buf = StreamEncoder::new(vec![]).finish().unwrap();
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decoder_round_trip() {
+        let values = (0..5000u64).map(|v| v << (v % 61)).collect::<Vec<_>>();
+
+        // chunks much larger and smaller than the SIMD decoders' scalar tail
+        for &chunk_len in &[8, 48, 64, 4096] {
+            let mut encoder = StreamEncoder::with_chunk_len(vec![], chunk_len);
+            encoder.extend(&values).unwrap();
+            let buf = encoder.finish().unwrap();
+
+            let out = StreamDecoder::new(&buf[..])
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(out, values);
+
+            let mut decoder = StreamDecoder::new(&buf[..]);
+            let mut out = vec![0; 3000];
+            assert_eq!(decoder.read_values(&mut out).unwrap(), 3000);
+            assert_eq!(out, &values[..3000]);
+            assert_eq!(decoder.read_values(&mut out).unwrap(), 2000);
+            assert_eq!(&out[..2000], &values[3000..]);
+            assert_eq!(decoder.read_values(&mut out).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn decoder_truncated() {
+        let mut encoder = StreamEncoder::with_chunk_len(vec![], 64);
+        encoder.extend(&[1 << 40; 100]).unwrap();
+        let buf = encoder.finish().unwrap();
+
+        for len in [1, 10, buf.len() - 1] {
+            let err = StreamDecoder::new(&buf[..len])
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn decoder_bad_length() {
+        let mut buf = vec![];
+        write_varint(&mut buf, 8);
+        write_varint(&mut buf, 9);
+        buf.extend_from_slice(&[0; 3 + 9]);
+
+        let mut decoder = StreamDecoder::new(&buf[..]);
+        let err = decoder.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(decoder.next().is_none());
+
+        let mut decoder = StreamDecoder::new(&buf[..]);
+        let mut out = [0; 8];
+        let err = decoder.read_values(&mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(decoder.read_values(&mut out).unwrap(), 0);
+        assert!(decoder.next().is_none());
+    }
+
+    #[test]
+    fn decoder_bad_count() {
+        for count in [u64::MAX, (1 << 20) + 8] {
+            let mut buf = vec![];
+            write_varint(&mut buf, count);
+            write_varint(&mut buf, 0);
+            buf.extend_from_slice(&[0; 64]);
+
+            let mut decoder = StreamDecoder::new(&buf[..]);
+            let err = decoder.next().unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(decoder.next().is_none());
+        }
+
+        let mut encoder = StreamEncoder::with_chunk_len(vec![], 64);
+        encoder.extend(&[1; 100]).unwrap();
+        let buf = encoder.finish().unwrap();
+        let err = StreamDecoder::with_max_chunk_len(&buf[..], 32)
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let out = StreamDecoder::with_max_chunk_len(&buf[..], 64)
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(out, [1; 100]);
+    }
+}
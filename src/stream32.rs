@@ -0,0 +1,576 @@
+//! The classic 32 bit Stream VByte format.
+//!
+//! Each key is 2 bits, encoding lengths of 1 to 4 bytes, and the keys for 4 values are packed
+//! into each key byte starting from the low bits. All of the keys precede all of the data. This is
+//! the layout produced by the reference C implementation, so buffers can be exchanged with it.
+//!
+//! Values are encoded and decoded with scalar, SSSE3 or AVX2 kernels, depending on the CPU.
+use std::ptr;
+
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::engine::Backend;
+use crate::engine::Engine;
+use crate::DecodeError;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[derive(Copy, Clone)]
+#[repr(C, align(16))]
+struct Shuffle([i8; 16]);
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl Shuffle {
+    #[inline]
+    unsafe fn load(&self) -> __m128i {
+        _mm_load_si128(self.0.as_ptr() as *const __m128i)
+    }
+}
+
+static LENGTH: [u8; 256] = make_lengths();
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+static DECODE_SHUFFLE: [Shuffle; 256] = make_decode_shuffles();
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+static ENCODE_SHUFFLE: [Shuffle; 256] = make_encode_shuffles();
+
+const fn make_lengths() -> [u8; 256] {
+    let mut lengths = [0; 256];
+    let mut key = 0;
+    while key < 256 {
+        lengths[key] = ((key & 3) + (key >> 2 & 3) + (key >> 4 & 3) + (key >> 6 & 3) + 4) as u8;
+        key += 1;
+    }
+    lengths
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const fn make_decode_shuffles() -> [Shuffle; 256] {
+    let mut shuffles = [Shuffle([0; 16]); 256];
+    let mut key = 0;
+    while key < 256 {
+        let mut b = [-1; 16];
+        let mut next = 0;
+        let mut lane = 0;
+        while lane < 4 {
+            let len = (key >> (2 * lane) & 3) + 1;
+            let mut i = 0;
+            while i < len {
+                b[lane * 4 + i] = next;
+                next += 1;
+                i += 1;
+            }
+            lane += 1;
+        }
+        shuffles[key] = Shuffle(b);
+        key += 1;
+    }
+    shuffles
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const fn make_encode_shuffles() -> [Shuffle; 256] {
+    let mut shuffles = [Shuffle([0; 16]); 256];
+    let mut key = 0;
+    while key < 256 {
+        let mut b = [-1; 16];
+        let mut next = 0;
+        let mut lane = 0;
+        while lane < 4 {
+            let len = (key >> (2 * lane) & 3) + 1;
+            let mut i = 0;
+            while i < len {
+                b[next] = (lane * 4 + i) as i8;
+                next += 1;
+                i += 1;
+            }
+            lane += 1;
+        }
+        shuffles[key] = Shuffle(b);
+        key += 1;
+    }
+    shuffles
+}
+
+pub fn keys_len(values: usize) -> usize {
+    values.div_ceil(4)
+}
+
+pub fn max_compressed_len(values: usize) -> usize {
+    keys_len(values) + values * 4
+}
+
+/// Returns the length of the data section for `values` values, or `None` if `keys` is too short.
+pub fn compressed_data_len(values: usize, keys: &[u8]) -> Option<usize> {
+    let keys = keys.get(..keys_len(values))?;
+
+    let mut len = keys
+        .iter()
+        .map(|&key| LENGTH[key as usize] as usize)
+        .sum::<usize>();
+    let tail = values % 4;
+    if tail != 0 {
+        // each of the padding codes in the last key counted as at least one byte
+        let last = keys[keys.len() - 1] >> (2 * tail);
+        len -= LENGTH[last as usize] as usize - tail;
+    }
+
+    Some(len)
+}
+
+fn encode_single(value: u32, out: &mut *mut u8) -> u8 {
+    let code = if value < 1 << 8 {
+        0
+    } else if value < 1 << 16 {
+        1
+    } else if value < 1 << 24 {
+        2
+    } else {
+        3
+    };
+
+    unsafe {
+        let value = value.to_le();
+        ptr::copy_nonoverlapping(&value as *const u32 as *const u8, *out, code + 1);
+        *out = out.add(code + 1);
+    }
+    code as u8
+}
+
+/// Encodes `input` into separate key and data buffers, returning the number of data bytes written.
+///
+/// # Safety
+///
+/// `keys` must be at least `keys_len(input.len())` bytes long, and `data` must be large enough to
+/// hold the encoded data.
+pub unsafe fn encode_scalar(input: &[u32], keys: &mut [u8], data: &mut [u8]) -> usize {
+    debug_assert!(keys.len() >= keys_len(input.len()));
+
+    let mut dataptr = data.as_mut_ptr();
+    for (chunk, key) in input.chunks(4).zip(keys) {
+        *key = 0;
+        for (i, &value) in chunk.iter().enumerate() {
+            *key |= encode_single(value, &mut dataptr) << (2 * i);
+        }
+    }
+
+    let written = dataptr as usize - data.as_ptr() as usize;
+    debug_assert!(written <= data.len());
+    written
+}
+
+/// Encodes `input` into separate key and data buffers, returning the number of data bytes written.
+///
+/// # Safety
+///
+/// `keys` must be at least `keys_len(input.len())` bytes long, and `data` must be large enough to
+/// hold the encoded data. The CPU must support SSSE3.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "ssse3")]
+pub unsafe fn encode_ssse3(input: &[u32], keys: &mut [u8], data: &mut [u8]) -> usize {
+    debug_assert!(keys.len() >= keys_len(input.len()));
+
+    // maps a nibble of nonzero byte flags to the code for that lane
+    const CODES: [u8; 16] = [0, 0, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 3, 3, 3, 3];
+
+    let mut dataptr = data.as_mut_ptr();
+    let dataend = data.as_ptr().add(data.len());
+
+    // like the 64 bit encoder, each block stores all 16 bytes, so we have to switch over to the
+    // scalar path when we get close to the end of `data`.
+    let blocks = input.len() / 4;
+    let mut count = 0;
+    while count < blocks && dataend as usize - dataptr as usize >= 16 {
+        let values = _mm_loadu_si128(input.as_ptr().add(count * 4) as *const __m128i);
+
+        let zeros = _mm_cmpeq_epi8(values, _mm_setzero_si128());
+        let nonzero = !_mm_movemask_epi8(zeros) as usize;
+        let key = CODES[nonzero & 0xf]
+            | CODES[nonzero >> 4 & 0xf] << 2
+            | CODES[nonzero >> 8 & 0xf] << 4
+            | CODES[nonzero >> 12 & 0xf] << 6;
+
+        let shuffled = _mm_shuffle_epi8(values, ENCODE_SHUFFLE[key as usize].load());
+        _mm_storeu_si128(dataptr as *mut __m128i, shuffled);
+        dataptr = dataptr.add(LENGTH[key as usize] as usize);
+        *keys.get_unchecked_mut(count) = key;
+
+        count += 1;
+    }
+
+    let written = dataptr as usize - data.as_ptr() as usize;
+    written
+        + encode_scalar(
+            &input[count * 4..],
+            &mut keys[count..],
+            &mut data[written..],
+        )
+}
+
+/// Encodes `input` into separate key and data buffers, returning the number of data bytes written.
+///
+/// # Safety
+///
+/// `keys` must be at least `keys_len(input.len())` bytes long, and `data` must be large enough to
+/// hold the encoded data. The CPU must support AVX2.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+pub unsafe fn encode_avx(input: &[u32], keys: &mut [u8], data: &mut [u8]) -> usize {
+    debug_assert!(keys.len() >= keys_len(input.len()));
+    #[cfg(test)]
+    crate::avx2::record_call();
+
+    // maps a nibble of nonzero byte flags to the code for that lane
+    const CODES: [u8; 16] = [0, 0, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 3, 3, 3, 3];
+
+    let mut dataptr = data.as_mut_ptr();
+    let dataend = data.as_ptr().add(data.len());
+
+    // each 128 bit lane is packed with the SSSE3 tables and stored separately, so a pair of keys
+    // can write up to 32 bytes.
+    let pairs = input.len() / 8;
+    let mut count = 0;
+    while count < pairs && dataend as usize - dataptr as usize >= 32 {
+        let values = _mm256_loadu_si256(input.as_ptr().add(count * 8) as *const __m256i);
+
+        let zeros = _mm256_cmpeq_epi8(values, _mm256_setzero_si256());
+        let nonzero = !_mm256_movemask_epi8(zeros) as u32 as usize;
+        let key_low = CODES[nonzero & 0xf]
+            | CODES[nonzero >> 4 & 0xf] << 2
+            | CODES[nonzero >> 8 & 0xf] << 4
+            | CODES[nonzero >> 12 & 0xf] << 6;
+        let key_high = CODES[nonzero >> 16 & 0xf]
+            | CODES[nonzero >> 20 & 0xf] << 2
+            | CODES[nonzero >> 24 & 0xf] << 4
+            | CODES[nonzero >> 28 & 0xf] << 6;
+
+        let shuffle = _mm256_inserti128_si256(
+            _mm256_castsi128_si256(ENCODE_SHUFFLE[key_low as usize].load()),
+            ENCODE_SHUFFLE[key_high as usize].load(),
+            1,
+        );
+        let shuffled = _mm256_shuffle_epi8(values, shuffle);
+
+        _mm_storeu_si128(dataptr as *mut __m128i, _mm256_castsi256_si128(shuffled));
+        dataptr = dataptr.add(LENGTH[key_low as usize] as usize);
+        _mm_storeu_si128(
+            dataptr as *mut __m128i,
+            _mm256_extracti128_si256(shuffled, 1),
+        );
+        dataptr = dataptr.add(LENGTH[key_high as usize] as usize);
+
+        *keys.get_unchecked_mut(count * 2) = key_low;
+        *keys.get_unchecked_mut(count * 2 + 1) = key_high;
+
+        count += 1;
+    }
+
+    let written = dataptr as usize - data.as_ptr() as usize;
+    written
+        + encode_scalar(
+            &input[count * 8..],
+            &mut keys[count * 2..],
+            &mut data[written..],
+        )
+}
+
+fn decode_single(ptr: &mut *const u8, code: u8) -> u32 {
+    let len = code as usize + 1;
+    let mut value = 0u32;
+    unsafe {
+        ptr::copy_nonoverlapping(*ptr, &mut value as *mut u32 as *mut u8, len);
+        *ptr = ptr.add(len);
+    }
+    u32::from_le(value)
+}
+
+/// Decodes `output.len()` values from separate key and data buffers, returning the number of data
+/// bytes read.
+///
+/// # Safety
+///
+/// `keys` must be at least `keys_len(output.len())` bytes long, and `data` must hold all of the
+/// encoded data.
+pub unsafe fn decode_scalar(output: &mut [u32], keys: &[u8], data: &[u8]) -> usize {
+    debug_assert!(keys.len() >= keys_len(output.len()));
+
+    let mut dataptr = data.as_ptr();
+    for (chunk, &key) in output.chunks_mut(4).zip(keys) {
+        for (i, output) in chunk.iter_mut().enumerate() {
+            *output = decode_single(&mut dataptr, key >> (2 * i) & 3);
+        }
+    }
+
+    let read = dataptr as usize - data.as_ptr() as usize;
+    debug_assert!(read <= data.len());
+    read
+}
+
+/// Decodes `output.len()` values from separate key and data buffers, returning the number of data
+/// bytes read.
+///
+/// # Safety
+///
+/// `keys` must be at least `keys_len(output.len())` bytes long, and `data` must hold all of the
+/// encoded data. The CPU must support SSSE3.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "ssse3")]
+pub unsafe fn decode_ssse3(output: &mut [u32], keys: &[u8], data: &[u8]) -> usize {
+    debug_assert!(keys.len() >= keys_len(output.len()));
+
+    // each block loads 16 bytes of data but may only consume 4 of them. Leaving the last 12
+    // values to the scalar path guarantees that there are always 16 real bytes left to load.
+    let blocks = output.len().saturating_sub(12) / 4;
+
+    let mut dataptr = data.as_ptr();
+    for i in 0..blocks {
+        let key = *keys.get_unchecked(i);
+        debug_assert!(dataptr.add(16) <= data.as_ptr().add(data.len()));
+        let values = _mm_loadu_si128(dataptr as *const __m128i);
+        let shuffled = _mm_shuffle_epi8(values, DECODE_SHUFFLE[key as usize].load());
+        _mm_storeu_si128(output.as_mut_ptr().add(i * 4) as *mut __m128i, shuffled);
+        dataptr = dataptr.add(LENGTH[key as usize] as usize);
+    }
+
+    let read = dataptr as usize - data.as_ptr() as usize;
+    read + decode_scalar(&mut output[blocks * 4..], &keys[blocks..], &data[read..])
+}
+
+/// Decodes `output.len()` values from separate key and data buffers, returning the number of data
+/// bytes read.
+///
+/// # Safety
+///
+/// `keys` must be at least `keys_len(output.len())` bytes long, and `data` must hold all of the
+/// encoded data. The CPU must support AVX2.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+pub unsafe fn decode_avx(output: &mut [u32], keys: &[u8], data: &[u8]) -> usize {
+    debug_assert!(keys.len() >= keys_len(output.len()));
+    #[cfg(test)]
+    crate::avx2::record_call();
+
+    // the high lane loads 16 bytes from the start of its own data, so like the SSSE3 decoder we
+    // leave the last 12 values to the scalar path.
+    let pairs = output.len().saturating_sub(12) / 8;
+
+    let mut dataptr = data.as_ptr();
+    for i in 0..pairs {
+        let key_low = *keys.get_unchecked(i * 2);
+        let key_high = *keys.get_unchecked(i * 2 + 1);
+        let high_ptr = dataptr.add(LENGTH[key_low as usize] as usize);
+        debug_assert!(high_ptr.add(16) <= data.as_ptr().add(data.len()));
+
+        let values = _mm256_inserti128_si256(
+            _mm256_castsi128_si256(_mm_loadu_si128(dataptr as *const __m128i)),
+            _mm_loadu_si128(high_ptr as *const __m128i),
+            1,
+        );
+        let shuffle = _mm256_inserti128_si256(
+            _mm256_castsi128_si256(DECODE_SHUFFLE[key_low as usize].load()),
+            DECODE_SHUFFLE[key_high as usize].load(),
+            1,
+        );
+        let shuffled = _mm256_shuffle_epi8(values, shuffle);
+        _mm256_storeu_si256(output.as_mut_ptr().add(i * 8) as *mut __m256i, shuffled);
+        dataptr = high_ptr.add(LENGTH[key_high as usize] as usize);
+    }
+
+    let read = dataptr as usize - data.as_ptr() as usize;
+    read + decode_scalar(&mut output[pairs * 8..], &keys[pairs * 2..], &data[read..])
+}
+
+/// Encodes `input` into `buf`, returning the number of bytes written.
+///
+/// # Panics
+///
+/// Panics if `buf` is smaller than `max_compressed_len(input.len())`.
+pub fn encode(input: &[u32], buf: &mut [u8]) -> usize {
+    Engine::new().encode_u32(input, buf)
+}
+
+/// Decodes `output.len()` values from `buf`, returning the number of bytes consumed.
+///
+/// # Panics
+///
+/// Panics if `buf` is truncated.
+pub fn decode(output: &mut [u32], buf: &[u8]) -> usize {
+    Engine::new().decode_u32(output, buf)
+}
+
+/// Like `decode`, but returns an error rather than panicking if `buf` is truncated.
+pub fn try_decode(output: &mut [u32], buf: &[u8]) -> Result<usize, DecodeError> {
+    Engine::new().try_decode_u32(output, buf)
+}
+
+impl Engine {
+    /// Like `stream32::encode`, using this engine's backend.
+    pub fn encode_u32(&self, input: &[u32], buf: &mut [u8]) -> usize {
+        assert!(buf.len() >= max_compressed_len(input.len()));
+        let keys_len = keys_len(input.len());
+        let (keys, data) = buf.split_at_mut(keys_len);
+
+        let written = unsafe {
+            match self.backend() {
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                Backend::Avx2 => encode_avx(input, keys, data),
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                Backend::Ssse3 => encode_ssse3(input, keys, data),
+                _ => encode_scalar(input, keys, data),
+            }
+        };
+
+        keys_len + written
+    }
+
+    /// Like `stream32::decode`, using this engine's backend.
+    pub fn decode_u32(&self, output: &mut [u32], buf: &[u8]) -> usize {
+        match self.try_decode_u32(output, buf) {
+            Ok(read) => read,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Like `stream32::try_decode`, using this engine's backend.
+    pub fn try_decode_u32(&self, output: &mut [u32], buf: &[u8]) -> Result<usize, DecodeError> {
+        let keys_len = keys_len(output.len());
+        let data_len = match compressed_data_len(output.len(), buf) {
+            Some(data_len) => data_len,
+            None => {
+                return Err(DecodeError::KeysTruncated {
+                    needed: keys_len,
+                    available: buf.len(),
+                })
+            }
+        };
+
+        let (keys, data) = buf.split_at(keys_len);
+        if data.len() < data_len {
+            return Err(DecodeError::DataTruncated {
+                needed: data_len,
+                available: data.len(),
+            });
+        }
+
+        let data = &data[..data_len];
+        let read = unsafe {
+            match self.backend() {
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                Backend::Avx2 => decode_avx(output, keys, data),
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                Backend::Ssse3 => decode_ssse3(output, keys, data),
+                _ => decode_scalar(output, keys, data),
+            }
+        };
+        debug_assert_eq!(read, data_len);
+
+        Ok(keys_len + read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn values() -> Vec<u32> {
+        (0..4090)
+            .map(|v| (v * (u32::MAX / 4090)) >> (v % 4 * 8))
+            .collect()
+    }
+
+    #[test]
+    fn reference_layout() {
+        let values = [0, 1 << 8, 1 << 16, 1 << 24, 0x0605_0403];
+        let mut buf = vec![0; max_compressed_len(values.len())];
+        let written = encode(&values, &mut buf);
+
+        #[rustfmt::skip]
+        let expected = [
+            0b11_10_01_00, 0b00_00_00_11,
+            0,
+            0, 1,
+            0, 0, 1,
+            0, 0, 0, 1,
+            3, 4, 5, 6,
+        ];
+        assert_eq!(&buf[..written], &expected[..]);
+
+        let mut out = [0; 5];
+        assert_eq!(decode(&mut out, &expected), expected.len());
+        assert_eq!(out, values);
+    }
+
+    #[test]
+    fn round_trip() {
+        for &count in &[0, 1, 3, 4, 5, 15, 16, 17, 100, 4090] {
+            let values = &values()[..count];
+            let mut buf = vec![0; max_compressed_len(values.len())];
+            let written = encode(values, &mut buf);
+
+            let mut out = vec![0; values.len()];
+            assert_eq!(try_decode(&mut out, &buf[..written]), Ok(written));
+            assert_eq!(values, &out[..]);
+
+            if written > 0 {
+                assert!(try_decode(&mut out, &buf[..written - 1]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn match_scalar() {
+        unsafe {
+            let values = values();
+            let mut keys1 = vec![0; keys_len(values.len())];
+            let mut data1 = vec![0; values.len() * 4];
+            let written1 = encode_scalar(&values, &mut keys1, &mut data1);
+
+            let mut keys2 = vec![0; keys_len(values.len())];
+            let mut data2 = vec![0; written1];
+            let written2 = encode_ssse3(&values, &mut keys2, &mut data2);
+
+            assert_eq!(keys1, keys2);
+            assert_eq!(&data1[..written1], &data2[..]);
+            assert_eq!(written1, written2);
+
+            let mut out1 = vec![0; values.len()];
+            decode_scalar(&mut out1, &keys1, &data1[..written1]);
+            let mut out2 = vec![0; values.len()];
+            decode_ssse3(&mut out2, &keys1, &data1[..written1]);
+            assert_eq!(values, out1);
+            assert_eq!(values, out2);
+        }
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn match_avx() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+
+        for &count in &[0, 7, 8, 20, 21, 100, 4090] {
+            let values = &values()[..count];
+            unsafe {
+                let mut keys1 = vec![0; keys_len(values.len())];
+                let mut data1 = vec![0; values.len() * 4];
+                let written1 = encode_scalar(values, &mut keys1, &mut data1);
+
+                let mut keys2 = vec![0; keys_len(values.len())];
+                let mut data2 = vec![0; written1];
+                let written2 = encode_avx(values, &mut keys2, &mut data2);
+
+                assert_eq!(keys1, keys2);
+                assert_eq!(&data1[..written1], &data2[..]);
+                assert_eq!(written1, written2);
+
+                let mut out = vec![0; values.len()];
+                assert_eq!(decode_avx(&mut out, &keys1, &data1[..written1]), written1);
+                assert_eq!(values, &out[..]);
+            }
+        }
+    }
+}
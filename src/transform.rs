@@ -0,0 +1,77 @@
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+/// A reversible mapping applied to values as they pass through the encoding kernels.
+///
+/// Transforms are stateful, and see values strictly in order. The AVX2 methods process 4 values
+/// at a time, and the kernels may switch from those to the scalar methods partway through.
+pub trait Transform {
+    fn encode(&mut self, value: u64) -> u64;
+
+    fn decode(&mut self, value: u64) -> u64;
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    unsafe fn encode_avx(&mut self, values: __m256i) -> __m256i;
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    unsafe fn decode_avx(&mut self, values: __m256i) -> __m256i;
+}
+
+pub struct Plain;
+
+impl Transform for Plain {
+    #[inline]
+    fn encode(&mut self, value: u64) -> u64 {
+        value
+    }
+
+    #[inline]
+    fn decode(&mut self, value: u64) -> u64 {
+        value
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[inline]
+    unsafe fn encode_avx(&mut self, values: __m256i) -> __m256i {
+        values
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[inline]
+    unsafe fn decode_avx(&mut self, values: __m256i) -> __m256i {
+        values
+    }
+}
+
+/// Shifts each 64 bit lane up by one, filling the bottom lane with the top lane of `prev`.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+#[inline]
+pub unsafe fn shift_in_avx(values: __m256i, prev: __m256i) -> __m256i {
+    let shifted = _mm256_permute4x64_epi64(values, 0b10_01_00_00);
+    let prev = _mm256_permute4x64_epi64(prev, 0b11_11_11_11);
+    _mm256_blend_epi32(shifted, prev, 0b0000_0011)
+}
+
+/// Computes the running sum of the 64 bit lanes, starting from the top lane of `prev`.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+#[inline]
+pub unsafe fn prefix_sum_avx(values: __m256i, prev: __m256i) -> __m256i {
+    let zero = _mm256_setzero_si256();
+
+    // [a, b, c, d] + [0, a, b, c]
+    let shifted = _mm256_permute4x64_epi64(values, 0b10_01_00_00);
+    let shifted = _mm256_blend_epi32(shifted, zero, 0b0000_0011);
+    let sums = _mm256_add_epi64(values, shifted);
+
+    // [a, a+b, b+c, c+d] + [0, 0, a, a+b]
+    let shifted = _mm256_permute4x64_epi64(sums, 0b01_00_00_00);
+    let shifted = _mm256_blend_epi32(shifted, zero, 0b0000_1111);
+    let sums = _mm256_add_epi64(sums, shifted);
+
+    let prev = _mm256_permute4x64_epi64(prev, 0b11_11_11_11);
+    _mm256_add_epi64(sums, prev)
+}
@@ -0,0 +1,354 @@
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+use std::slice;
+
+use crate::delta::Delta;
+use crate::engine::Engine;
+use crate::transform::Transform;
+use crate::{decode_with, encode_with};
+
+#[inline]
+fn zigzag_encode(value: u64) -> u64 {
+    (value << 1) ^ ((value as i64 >> 63) as u64)
+}
+
+#[inline]
+fn zigzag_decode(value: u64) -> u64 {
+    (value >> 1) ^ (value & 1).wrapping_neg()
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn zigzag_encode_avx(values: __m256i) -> __m256i {
+    // there's no 64 bit arithmetic shift, so build the sign mask with a comparison instead
+    let sign = _mm256_cmpgt_epi64(_mm256_setzero_si256(), values);
+    _mm256_xor_si256(_mm256_slli_epi64(values, 1), sign)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn zigzag_decode_avx(values: __m256i) -> __m256i {
+    let low = _mm256_and_si256(values, _mm256_set1_epi64x(1));
+    let sign = _mm256_sub_epi64(_mm256_setzero_si256(), low);
+    _mm256_xor_si256(_mm256_srli_epi64(values, 1), sign)
+}
+
+pub struct Zigzag;
+
+impl Transform for Zigzag {
+    #[inline]
+    fn encode(&mut self, value: u64) -> u64 {
+        zigzag_encode(value)
+    }
+
+    #[inline]
+    fn decode(&mut self, value: u64) -> u64 {
+        zigzag_decode(value)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx2")]
+    #[inline]
+    unsafe fn encode_avx(&mut self, values: __m256i) -> __m256i {
+        zigzag_encode_avx(values)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx2")]
+    #[inline]
+    unsafe fn decode_avx(&mut self, values: __m256i) -> __m256i {
+        zigzag_decode_avx(values)
+    }
+}
+
+pub struct DeltaZigzag(Delta);
+
+impl DeltaZigzag {
+    pub fn new(initial: u64) -> DeltaZigzag {
+        DeltaZigzag(Delta::new(initial))
+    }
+}
+
+impl Transform for DeltaZigzag {
+    #[inline]
+    fn encode(&mut self, value: u64) -> u64 {
+        zigzag_encode(self.0.encode(value))
+    }
+
+    #[inline]
+    fn decode(&mut self, value: u64) -> u64 {
+        self.0.decode(zigzag_decode(value))
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx2")]
+    #[inline]
+    unsafe fn encode_avx(&mut self, values: __m256i) -> __m256i {
+        zigzag_encode_avx(self.0.encode_avx(values))
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx2")]
+    #[inline]
+    unsafe fn decode_avx(&mut self, values: __m256i) -> __m256i {
+        self.0.decode_avx(zigzag_decode_avx(values))
+    }
+}
+
+/// Zigzag encodes the differences between consecutive deltas.
+pub struct DeltaOfDelta {
+    values: Delta,
+    deltas: Delta,
+}
+
+impl DeltaOfDelta {
+    pub fn new(initial: u64) -> DeltaOfDelta {
+        DeltaOfDelta {
+            values: Delta::new(initial),
+            deltas: Delta::new(0),
+        }
+    }
+}
+
+impl Transform for DeltaOfDelta {
+    #[inline]
+    fn encode(&mut self, value: u64) -> u64 {
+        zigzag_encode(self.deltas.encode(self.values.encode(value)))
+    }
+
+    #[inline]
+    fn decode(&mut self, value: u64) -> u64 {
+        self.values.decode(self.deltas.decode(zigzag_decode(value)))
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx2")]
+    #[inline]
+    unsafe fn encode_avx(&mut self, values: __m256i) -> __m256i {
+        zigzag_encode_avx(self.deltas.encode_avx(self.values.encode_avx(values)))
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx2")]
+    #[inline]
+    unsafe fn decode_avx(&mut self, values: __m256i) -> __m256i {
+        self.values
+            .decode_avx(self.deltas.decode_avx(zigzag_decode_avx(values)))
+    }
+}
+
+fn as_u64s(values: &[i64]) -> &[u64] {
+    unsafe { slice::from_raw_parts(values.as_ptr() as *const u64, values.len()) }
+}
+
+fn as_u64s_mut(values: &mut [i64]) -> &mut [u64] {
+    unsafe { slice::from_raw_parts_mut(values.as_mut_ptr() as *mut u64, values.len()) }
+}
+
+/// Encodes signed values into `buf`, mapping values of small magnitude to short encodings.
+pub fn encode_i64(input: &[i64], buf: &mut [u8]) -> usize {
+    Engine::new().encode_i64(input, buf)
+}
+
+/// Decodes values encoded by `encode_i64`.
+pub fn decode_i64(output: &mut [i64], buf: &[u8]) -> usize {
+    Engine::new().decode_i64(output, buf)
+}
+
+/// Encodes the signed differences between consecutive values of `input` into `buf`.
+///
+/// The first value is encoded relative to `initial`. Unlike `encode_delta`, differences of small
+/// magnitude are encoded compactly whether they are positive or negative.
+pub fn encode_delta_i64(input: &[i64], initial: i64, buf: &mut [u8]) -> usize {
+    Engine::new().encode_delta_i64(input, initial, buf)
+}
+
+/// Decodes values encoded by `encode_delta_i64` with the same `initial` value.
+pub fn decode_delta_i64(output: &mut [i64], initial: i64, buf: &[u8]) -> usize {
+    Engine::new().decode_delta_i64(output, initial, buf)
+}
+
+/// Encodes the signed differences between consecutive deltas of `input` into `buf`.
+///
+/// The first value is encoded relative to `initial`, with an initial delta of 0. Regularly spaced
+/// values like timestamps of periodic samples take about one byte each.
+pub fn encode_delta_of_delta(input: &[u64], initial: u64, buf: &mut [u8]) -> usize {
+    Engine::new().encode_delta_of_delta(input, initial, buf)
+}
+
+/// Decodes values encoded by `encode_delta_of_delta` with the same `initial` value.
+pub fn decode_delta_of_delta(output: &mut [u64], initial: u64, buf: &[u8]) -> usize {
+    Engine::new().decode_delta_of_delta(output, initial, buf)
+}
+
+impl Engine {
+    /// Like the crate's `encode_i64`, using this engine's backend.
+    pub fn encode_i64(&self, input: &[i64], buf: &mut [u8]) -> usize {
+        encode_with(self.backend(), as_u64s(input), buf, &mut Zigzag)
+    }
+
+    /// Like the crate's `decode_i64`, using this engine's backend.
+    pub fn decode_i64(&self, output: &mut [i64], buf: &[u8]) -> usize {
+        decode_with(self.backend(), as_u64s_mut(output), buf, &mut Zigzag)
+    }
+
+    /// Like the crate's `encode_delta_i64`, using this engine's backend.
+    pub fn encode_delta_i64(&self, input: &[i64], initial: i64, buf: &mut [u8]) -> usize {
+        encode_with(
+            self.backend(),
+            as_u64s(input),
+            buf,
+            &mut DeltaZigzag::new(initial as u64),
+        )
+    }
+
+    /// Like the crate's `decode_delta_i64`, using this engine's backend.
+    pub fn decode_delta_i64(&self, output: &mut [i64], initial: i64, buf: &[u8]) -> usize {
+        decode_with(
+            self.backend(),
+            as_u64s_mut(output),
+            buf,
+            &mut DeltaZigzag::new(initial as u64),
+        )
+    }
+
+    /// Like the crate's `encode_delta_of_delta`, using this engine's backend.
+    pub fn encode_delta_of_delta(&self, input: &[u64], initial: u64, buf: &mut [u8]) -> usize {
+        encode_with(self.backend(), input, buf, &mut DeltaOfDelta::new(initial))
+    }
+
+    /// Like the crate's `decode_delta_of_delta`, using this engine's backend.
+    pub fn decode_delta_of_delta(&self, output: &mut [u64], initial: u64, buf: &[u8]) -> usize {
+        decode_with(self.backend(), output, buf, &mut DeltaOfDelta::new(initial))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::max_compressed_len;
+
+    #[test]
+    fn zigzag() {
+        let tests = [
+            (0, 0),
+            (-1, 1),
+            (1, 2),
+            (-2, 3),
+            (i64::MAX, u64::MAX - 1),
+            (i64::MIN, u64::MAX),
+        ];
+        for &(value, encoded) in &tests {
+            assert_eq!(zigzag_encode(value as u64), encoded);
+            assert_eq!(zigzag_decode(encoded), value as u64);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let values = (-2045..2045i64).map(|v| v * v * v).collect::<Vec<_>>();
+        let mut buf = vec![0; max_compressed_len(values.len())];
+        let written = encode_i64(&values, &mut buf);
+
+        let mut out = vec![0; values.len()];
+        decode_i64(&mut out, &buf[..written]);
+        assert_eq!(values, out);
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let values = (0..4090i64)
+            .map(|v| 20_000 + (v % 13 - 6) * 50 - v / 3)
+            .collect::<Vec<_>>();
+        let mut buf = vec![0; max_compressed_len(values.len())];
+        let written = encode_delta_i64(&values, 20_000, &mut buf);
+        assert!(written < values.len() * 2);
+
+        let mut out = vec![0; values.len()];
+        decode_delta_i64(&mut out, 20_000, &buf[..written]);
+        assert_eq!(values, out);
+    }
+
+    #[test]
+    fn delta_of_delta_round_trip() {
+        let values = (0..4090u64)
+            .map(|v| 1_700_000_000_000 + v * 10_000 + v * 7919 % 3)
+            .collect::<Vec<_>>();
+        let mut buf = vec![0; max_compressed_len(values.len())];
+        let written = encode_delta_of_delta(&values, 1_700_000_000_000, &mut buf);
+        assert!(written < values.len() * 3 / 2);
+
+        let mut out = vec![0; values.len()];
+        decode_delta_of_delta(&mut out, 1_700_000_000_000, &buf[..written]);
+        assert_eq!(values, out);
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn match_scalar() {
+        use crate::avx2::{decode_avx_with, encode_avx_with};
+        use crate::{decode_scalar_with, encode_scalar_with, keys_len};
+
+        unsafe {
+            let values = (-2045..2045i64)
+                .map(|v| (v * 7919) as u64)
+                .collect::<Vec<_>>();
+            let mut keys1 = vec![0; keys_len(values.len())];
+            let mut data1 = vec![0; values.len() * 8];
+            let written1 = encode_scalar_with(&values, &mut keys1, &mut data1, &mut Zigzag);
+
+            let mut keys2 = vec![0; keys_len(values.len())];
+            let mut data2 = vec![0; values.len() * 8];
+            let written2 = encode_avx_with(&values, &mut keys2, &mut data2, &mut Zigzag);
+
+            assert_eq!(keys1, keys2);
+            assert_eq!(data1, data2);
+            assert_eq!(written1, written2);
+
+            let mut out1 = vec![0; values.len()];
+            decode_scalar_with(&mut out1, &keys1, &data1, &mut Zigzag);
+            let mut out2 = vec![0; values.len()];
+            decode_avx_with(&mut out2, &keys1, &data1, &mut Zigzag);
+            assert_eq!(values, out1);
+            assert_eq!(values, out2);
+        }
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn delta_of_delta_match_scalar() {
+        use crate::avx2::{decode_avx_with, encode_avx_with};
+        use crate::{decode_scalar_with, encode_scalar_with, keys_len};
+
+        unsafe {
+            let values = (0..4090u64)
+                .map(|v| v * v * 31 + v % 5 * 1000)
+                .collect::<Vec<_>>();
+            let mut keys1 = vec![0; keys_len(values.len())];
+            let mut data1 = vec![0; values.len() * 8];
+            let written1 =
+                encode_scalar_with(&values, &mut keys1, &mut data1, &mut DeltaOfDelta::new(9));
+
+            let mut keys2 = vec![0; keys_len(values.len())];
+            let mut data2 = vec![0; values.len() * 8];
+            let written2 =
+                encode_avx_with(&values, &mut keys2, &mut data2, &mut DeltaOfDelta::new(9));
+
+            assert_eq!(keys1, keys2);
+            assert_eq!(data1, data2);
+            assert_eq!(written1, written2);
+
+            let mut out1 = vec![0; values.len()];
+            decode_scalar_with(&mut out1, &keys1, &data1, &mut DeltaOfDelta::new(9));
+            let mut out2 = vec![0; values.len()];
+            decode_avx_with(&mut out2, &keys1, &data1, &mut DeltaOfDelta::new(9));
+            assert_eq!(values, out1);
+            assert_eq!(values, out2);
+        }
+    }
+}